@@ -1,9 +1,19 @@
+use std::io::{self, Read, Write};
+use std::fs::{self, File};
 use nodes::PathInfo;
-use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use percent_encoding::{utf8_percent_encode, percent_decode, NON_ALPHANUMERIC};
+use chrono::{DateTime, Local};
+use filetime::{set_file_times, FileTime};
+use sha2::{Digest, Sha256};
+use compress::{self, Compression};
+
+/// Extension of partially written exports. As backup filenames are percent
+/// encoded they never contain a '.' other then that of their own extension.
+pub const TEMP_EXT: &str = "part";
 
 /// get site from path
 pub fn extract_site(path: &str) -> &str {
-    path.splitn(3, "/").skip(1).next().unwrap_or("--blank--")
+    path.split('/').nth(1).unwrap_or("--blank--")
 }
 
 pub fn drop_site(path: &str) -> &str {
@@ -14,19 +24,173 @@ pub fn drop_site(path: &str) -> &str {
 
 /// Turn archive dir, extension, and PathInfo into /<repo>/<site>/<archive_extension>
 pub fn archive_path(dir: &str, ext: &str, path: &PathInfo) -> String {
-    format!("{}/{}/{}/{}", dir.to_string(), ext, &path.repo_type, extract_site(&path.path))
+    format!("{}/{}", dir, archive_key(ext, path))
+}
+
+/// Turn extension and PathInfo into <archive_extension>/<repo>/<site>, the
+/// archive path within archive dir by which storage keys exports
+pub fn archive_key(ext: &str, path: &PathInfo) -> String {
+    format!("{}/{}/{}", ext, &path.repo_type, extract_site(&path.path))
 }
 
-/// Turn PathInfo into percent_encoded(path-minus-site).xml
-pub fn backup_filename(path: &PathInfo) -> String {
-    format!("{}.xml", utf8_percent_encode(&format!("{}", drop_site(&path.path)), NON_ALPHANUMERIC)) //.to_string()
+/// Turn PathInfo into percent_encoded(path-minus-site).xml followed by the suffix of its compression
+pub fn backup_filename(path: &PathInfo, compression: Compression) -> String {
+    format!("{}.xml{}", utf8_percent_encode(drop_site(&path.path), NON_ALPHANUMERIC), compression.suffix()) //.to_string()
+}
+
+/// Turn site and backup filename back into the absolute path of the node,
+/// the reverse of extract_site and backup_filename whatever its compression.
+pub fn node_path(site: &str, filename: &str) -> Option<String> {
+    let compressed = filename.strip_suffix(Compression::of(filename).suffix())?;
+    let encoded = compressed.strip_suffix(".xml")?;
+    let path = percent_decode(encoded.as_bytes()).decode_utf8().ok()?;
+    if path == site {
+        Some(format!("/{}", site))
+    } else {
+        Some(format!("/{}/{}", site, path))
+    }
+}
+
+/// Temporary file used while writing out file
+pub fn temp_filename(file: &str) -> String {
+    format!("{}.{}", file, TEMP_EXT)
+}
+
+/// Turn last_modified timestamp into a file time
+pub fn file_time(last_modified: &DateTime<Local>) -> FileTime {
+    FileTime::from_unix_time(last_modified.timestamp(), last_modified.timestamp_subsec_nanos())
+}
+
+/// Stream data to a temporary file next to file, set its modified time, and only
+/// then rename it into place so a bad copy never leaves a truncated file behind.
+/// The temporary file is removed upon any error.
+pub fn write_file<R: Read>(file: &str, data: &mut R, last_modified: Option<&DateTime<Local>>) -> io::Result<u64> {
+    write_file_with(file, last_modified, |temp| io::copy(data, temp))
+}
+
+/// Same as write_file where copy streams the data into the temporary file,
+/// so file is left as it was if copy fails part way through.
+pub fn write_file_with<F>(file: &str, last_modified: Option<&DateTime<Local>>, copy: F) -> io::Result<u64>
+    where F: FnOnce(&mut File) -> io::Result<u64>
+{
+    let temp_file = temp_filename(file);
+    let result = File::create(&temp_file).and_then(|mut temp| {
+        let size = copy(&mut temp)?;
+        temp.sync_all()?;
+        drop(temp);
+        if let Some(last_modified) = last_modified {
+            let timestamp = file_time(last_modified);
+            set_file_times(&temp_file, timestamp, timestamp)?;
+        }
+        fs::rename(&temp_file, file)?;
+        Ok(size)
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_file);
+    }
+    result
+}
+
+/// Remove any temporary files left behind under dir, returning the number removed.
+pub fn remove_temp_files(dir: &str) -> io::Result<usize> {
+    let mut removed = 0;
+    let suffix = format!(".{}", TEMP_EXT);
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            removed += remove_temp_files(&path.to_string_lossy())?;
+        } else if path.to_string_lossy().ends_with(&suffix) {
+            fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Archived file of path within its archive_path if one exists, whatever its compression
+pub fn archived_file(archive_path: &str, path: &PathInfo) -> Option<String> {
+    Compression::ALL.iter()
+        .map(|&compression| format!("{}/{}", archive_path, backup_filename(path, compression)))
+        .find(|file| fs::symlink_metadata(file).is_ok())
+}
+
+/// Modified time of file if it exists
+pub fn modified(file: &str) -> Option<DateTime<Local>> {
+    fs::metadata(file).and_then(|meta| meta.modified()).ok().map(DateTime::from)
+}
+
+/// Reader, or writer, computing the SHA-256 of everything read or written through it
+pub struct Hashing<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R> Hashing<R> {
+    pub fn new(inner: R) -> Hashing<R> {
+        Hashing{ inner, hasher: Sha256::new() }
+    }
+
+    /// Hex encoded SHA-256 of what was read or written
+    pub fn sha256(self) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Size and hex encoded SHA-256 of the export archived in file, decompressed
+pub fn sha256_file(file: &str) -> io::Result<(u64, String)> {
+    sha256(compress::open(file)?)
+}
+
+/// Size and hex encoded SHA-256 of everything read from export
+pub fn sha256<R: Read>(export: R) -> io::Result<(u64, String)> {
+    let mut hashing = Hashing::new(export);
+    let size = io::copy(&mut hashing, &mut io::sink())?;
+    Ok((size, hashing.sha256()))
+}
+
+/// Create an empty scratch directory for tests
+#[cfg(test)]
+pub fn test_dir(name: &str) -> String {
+    let dir = format!("{}/pagers-test-{}-{}", ::std::env::temp_dir().to_string_lossy(), name, ::std::process::id());
+    let _ = fs::remove_dir_all(&dir);
+    fs::DirBuilder::new().recursive(true).create(&dir).unwrap();
+    dir
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use repos::RepoType;
-    use chrono::{DateTime, Local};
+    use std::fs::DirBuilder;
+
+    struct BadRead;
+
+    impl Read for BadRead {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::other("connection reset"))
+        }
+    }
 
     #[test]
     fn test_archive_path() {
@@ -37,7 +201,7 @@ mod tests {
             repo_type: RepoType::Website,
             last_modified: Some("2018-05-05T08:59:29.261-05:00".parse::<DateTime<Local>>().unwrap()),
         };
-        assert_eq!(archive_path(archive_dir, &archive_ext, &path), "/mnt/nfs/archive/20180506/website/gato");
+        assert_eq!(archive_path(archive_dir, archive_ext, &path), "/mnt/nfs/archive/20180506/website/gato");
     }
 
     #[test]
@@ -47,6 +211,77 @@ mod tests {
             repo_type: RepoType::Website,
             last_modified: Some("2018-05-05T08:59:29.261-05:00".parse::<DateTime<Local>>().unwrap()),
         };
-        assert_eq!(backup_filename(&path), "subpage1%2Fsubpage2%2Ffile%20name%2Eodf.xml");
+        assert_eq!(backup_filename(&path, Compression::None), "subpage1%2Fsubpage2%2Ffile%20name%2Eodf.xml");
+        assert_eq!(backup_filename(&path, Compression::Zstd), "subpage1%2Fsubpage2%2Ffile%20name%2Eodf.xml.zst");
+    }
+
+    #[test]
+    fn test_node_path() {
+        assert_eq!(node_path("gato", "subpage1%2Fsubpage2%2Ffile%20name%2Eodf.xml"), Some("/gato/subpage1/subpage2/file name.odf".to_string()));
+        assert_eq!(node_path("gato", "gato.xml"), Some("/gato".to_string()));
+        assert_eq!(node_path("gato", "gato.xml.gz"), Some("/gato".to_string()));
+        assert_eq!(node_path("gato", "about.xml.zst"), Some("/gato/about".to_string()));
+        assert_eq!(node_path("gato", "gato.xml.part"), None);
+        assert_eq!(node_path("gato", "gato.xml.zst.part"), None);
+    }
+
+    #[test]
+    fn test_write_file() {
+        let dir = test_dir("write");
+        let file = format!("{}/page.xml", dir);
+        let last_modified = "2018-05-05T08:59:29.261-05:00".parse::<DateTime<Local>>().unwrap();
+        let size = write_file(&file, &mut "<sv:node/>".as_bytes(), Some(&last_modified)).unwrap();
+        assert_eq!(size, 10);
+        assert_eq!(fs::read_to_string(&file).unwrap(), "<sv:node/>");
+        assert_eq!(FileTime::from_last_modification_time(&fs::metadata(&file).unwrap()), file_time(&last_modified));
+        assert!(fs::metadata(temp_filename(&file)).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_write_file_bad_copy() {
+        let dir = test_dir("bad-copy");
+        let file = format!("{}/page.xml", dir);
+        fs::write(&file, "good copy").unwrap();
+        assert!(write_file(&file, &mut BadRead, None).is_err());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "good copy");
+        assert!(fs::metadata(temp_filename(&file)).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sha256() {
+        let dir = test_dir("sha256");
+        let file = format!("{}/page.xml", dir);
+        let mut hashing = Hashing::new("<sv:node/>".as_bytes());
+        assert_eq!(write_file(&file, &mut hashing, None).unwrap(), 10);
+        let sha256 = hashing.sha256();
+        assert_eq!(sha256, "b80936834c3322f32424fe8a5b4df5732e10a0dced6b7de2c7003d82a7115fb0");
+        assert_eq!(sha256_file(&file).unwrap(), (10, sha256));
+        assert_eq!(Hashing::new("".as_bytes()).sha256(), "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_remove_temp_files() {
+        let dir = test_dir("sweep");
+        DirBuilder::new().recursive(true).create(format!("{}/dam/gato", dir)).unwrap();
+        fs::write(format!("{}/dam/gato/a%2Egif.xml", dir), "").unwrap();
+        fs::write(format!("{}/dam/gato/b%2Egif.xml.part", dir), "").unwrap();
+        assert_eq!(remove_temp_files(&dir).unwrap(), 1);
+        assert!(fs::metadata(format!("{}/dam/gato/a%2Egif.xml", dir)).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_archived_file() {
+        let dir = test_dir("archived");
+        let path = PathInfo{ repo_type: RepoType::Dam, path: "/gato/a.gif".to_string(), last_modified: None };
+        assert_eq!(archived_file(&dir, &path), None);
+        fs::write(format!("{}/a%2Egif.xml.zst", dir), "").unwrap();
+        assert_eq!(archived_file(&dir, &path), Some(format!("{}/a%2Egif.xml.zst", dir)));
+        fs::write(format!("{}/a%2Egif.xml", dir), "").unwrap();
+        assert_eq!(archived_file(&dir, &path), Some(format!("{}/a%2Egif.xml", dir)));
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
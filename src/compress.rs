@@ -0,0 +1,133 @@
+use std::io::{self, BufReader, Read, Write};
+use std::fs::File;
+use std::str::FromStr;
+use std::fmt::{self, Display};
+use failure::{Error, err_msg};
+use flate2::{self, read::MultiGzDecoder, write::GzEncoder};
+use zstd;
+
+/// Compression of archived exports, each variant marked by the suffix it adds
+/// to the .xml extension of backup filenames.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Every compression an archived file may have been written with
+    pub const ALL: [Compression; 3] = [Compression::None, Compression::Gzip, Compression::Zstd];
+
+    pub fn suffix(self) -> &'static str {
+        match self {
+            Compression::None => "",
+            Compression::Gzip => ".gz",
+            Compression::Zstd => ".zst",
+        }
+    }
+
+    /// Compression of an archived file judged by its filename
+    pub fn of(file: &str) -> Compression {
+        if file.ends_with(Compression::Gzip.suffix()) {
+            Compression::Gzip
+        } else if file.ends_with(Compression::Zstd.suffix()) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Compress everything copy writes into out, returning what copy returns
+    pub fn write<W, F>(self, out: W, copy: F) -> io::Result<u64>
+        where W: Write, F: FnOnce(&mut dyn Write) -> io::Result<u64>
+    {
+        match self {
+            Compression::None => {
+                let mut out = out;
+                copy(&mut out)
+            },
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(out, flate2::Compression::default());
+                let size = copy(&mut encoder)?;
+                encoder.finish()?;
+                Ok(size)
+            },
+            Compression::Zstd => {
+                let mut encoder = zstd::Encoder::new(out, zstd::DEFAULT_COMPRESSION_LEVEL)?;
+                let size = copy(&mut encoder)?;
+                encoder.finish()?;
+                Ok(size)
+            },
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(err_msg("Invalid value")),
+        }
+    }
+}
+
+impl Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        })
+    }
+}
+
+/// Open an archived file for reading, decompressing it per its filename
+pub fn open(file: &str) -> io::Result<Box<dyn Read + Send>> {
+    reader(File::open(file)?, Compression::of(file))
+}
+
+/// Read data decompressed with compression
+pub fn reader<R: Read + Send + 'static>(data: R, compression: Compression) -> io::Result<Box<dyn Read + Send>> {
+    Ok(match compression {
+        Compression::None => Box::new(data),
+        Compression::Gzip => Box::new(MultiGzDecoder::new(BufReader::new(data))),
+        Compression::Zstd => Box::new(zstd::Decoder::new(data)?),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use backup::test_dir;
+
+    #[test]
+    fn test_compression() {
+        let dir = test_dir("compress");
+        let export = "<sv:node sv:name=\"page\"><sv:property/></sv:node>\n".repeat(20);
+        for &compression in &Compression::ALL {
+            assert_eq!(compression.to_string().parse::<Compression>().unwrap(), compression);
+            let file = format!("{}/page.xml{}", dir, compression.suffix());
+            assert_eq!(Compression::of(&file), compression);
+            let size = compression.write(File::create(&file).unwrap(), |out| io::copy(&mut export.as_bytes(), out)).unwrap();
+            assert_eq!(size, export.len() as u64);
+            let mut read = String::new();
+            open(&file).unwrap().read_to_string(&mut read).unwrap();
+            assert_eq!(read, export);
+            if compression != Compression::None {
+                assert!(fs::metadata(&file).unwrap().len() < size);
+                // a truncated file never reads back as a whole export
+                let compressed = fs::read(&file).unwrap();
+                fs::write(&file, &compressed[..compressed.len() - 8]).unwrap();
+                assert!(open(&file).and_then(|mut data| data.read_to_string(&mut String::new())).is_err());
+            }
+        }
+        assert!("lz4".parse::<Compression>().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::{HashMap, HashSet};
+use chrono::{DateTime, Local};
+use repos::{Repo, RepoType};
+use nodes::PathInfo;
+use manifest::Entry;
+use backup;
+
+/// Node that existed in the previous snapshot but no longer exists
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Deletion {
+    pub repo: RepoType,
+    pub site: String,
+    pub path: String,
+    pub last_modified: Option<DateTime<Local>>,
+    /// Last snapshot holding a copy of the node, if it had been archived
+    pub snapshot: Option<String>,
+}
+
+/// Everything listed while taking a snapshot. Only repos and sites that were
+/// successfully listed can tell if a node has since been deleted.
+/// Paths are left out when saved as they are found in the manifest.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Listing {
+    repos: HashSet<RepoType>,
+    failed_sites: HashSet<(RepoType, String)>,
+    #[serde(skip)]
+    paths: HashSet<(RepoType, String)>,
+}
+
+impl Listing {
+    pub fn new() -> Listing {
+        Listing::default()
+    }
+
+    /// Add everything listed by another thread
+    pub fn merge(&mut self, other: Listing) {
+        self.repos.extend(other.repos);
+        self.failed_sites.extend(other.failed_sites);
+        self.paths.extend(other.paths);
+    }
+
+    /// Sites of repo were listed
+    pub fn repo(&mut self, repo_type: RepoType) {
+        self.repos.insert(repo_type);
+    }
+
+    /// Paths of site could not be listed
+    pub fn failed_site(&mut self, site: &PathInfo) {
+        self.failed_sites.insert((site.repo_type, backup::extract_site(&site.path).to_string()));
+    }
+
+    pub fn path(&mut self, path: &PathInfo) {
+        self.paths.insert((path.repo_type, path.path.clone()));
+    }
+
+    /// Path recorded in the manifest by an earlier run of the snapshot
+    pub fn entry(&mut self, entry: &Entry) {
+        self.paths.insert((entry.repo, entry.path.clone()));
+    }
+
+    /// Determine if entry of a previous snapshot was deleted. Entries of repos or
+    /// sites no longer backed up, or which could not be listed, are not deleted.
+    pub fn is_deleted(&self, repos: &[Repo], entry: &Entry) -> bool {
+        self.repos.contains(&entry.repo)
+            && repos.iter().any(|repo| repo.repo_type == entry.repo && repo.has_site(&entry.site))
+            && !self.failed_sites.contains(&(entry.repo, entry.site.clone()))
+            && !self.paths.contains(&(entry.repo, entry.path.clone()))
+    }
+
+    /// Find the entries of the previous snapshot which have been deleted.
+    pub fn deletions(&self, repos: &[Repo], previous: Vec<Entry>, previous_ext: &str) -> Vec<Deletion> {
+        previous.into_iter()
+            .filter(|entry| self.is_deleted(repos, entry))
+            .map(|entry| Deletion{
+                snapshot: if entry.action.is_archived() { Some(previous_ext.to_string()) } else { None },
+                repo: entry.repo,
+                site: entry.site,
+                path: entry.path,
+                last_modified: entry.last_modified,
+            })
+            .collect()
+    }
+}
+
+/// Find the last snapshot holding a copy of each deletion the previous snapshot
+/// did not archive, such as when it failed or was skipped there, by walking back
+/// through the entries of earlier snapshots, newest first, only as far as needed.
+pub fn last_copies<I: IntoIterator<Item = (String, Vec<Entry>)>>(deletions: &mut [Deletion], earlier: I) {
+    let mut missing: HashMap<(RepoType, String), usize> = deletions.iter().enumerate()
+        .filter(|(_, deletion)| deletion.snapshot.is_none())
+        .map(|(i, deletion)| ((deletion.repo, deletion.path.clone()), i))
+        .collect();
+    for (ext, entries) in earlier {
+        if missing.is_empty() {
+            break;
+        }
+        for entry in entries.into_iter().filter(|entry| entry.action.is_archived()) {
+            if let Some(i) = missing.remove(&(entry.repo, entry.path)) {
+                deletions[i].snapshot = Some(ext.clone());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use repos;
+    use manifest::Action;
+
+    fn path(repo_type: RepoType, path: &str) -> PathInfo {
+        PathInfo{ repo_type, path: path.to_string(), last_modified: None }
+    }
+
+    #[test]
+    fn test_deletions() {
+        let repos = repos::new(r#"[{"dam": ["gato", "tx"]}, "website"]"#).unwrap();
+        let mut listing = Listing::new();
+        listing.repo(RepoType::Dam);
+        listing.path(&path(RepoType::Dam, "/gato/kept.gif"));
+        listing.failed_site(&path(RepoType::Dam, "/tx"));
+        let previous = vec![
+            Entry::new(&path(RepoType::Dam, "/gato/kept.gif"), Action::Linked, Some(1)),
+            Entry::new(&path(RepoType::Dam, "/gato/deleted.gif"), Action::Exported, Some(1)),
+            Entry::skipped(&path(RepoType::Dam, "/gato/failed.gif"), "Skip error type"),
+            Entry::new(&path(RepoType::Dam, "/tx/unlisted.gif"), Action::Linked, Some(1)),
+            Entry::new(&path(RepoType::Dam, "/other/not-backed-up.gif"), Action::Linked, Some(1)),
+            Entry::new(&path(RepoType::Website, "/gato/page"), Action::Linked, Some(1)),
+        ];
+        assert_eq!(listing.deletions(&repos, previous, "20180621"), vec![
+            Deletion{ repo: RepoType::Dam, site: "gato".to_string(), path: "/gato/deleted.gif".to_string(), last_modified: None, snapshot: Some("20180621".to_string()) },
+            Deletion{ repo: RepoType::Dam, site: "gato".to_string(), path: "/gato/failed.gif".to_string(), last_modified: None, snapshot: None },
+        ]);
+    }
+
+    #[test]
+    fn test_last_copies() {
+        let deletion = |path: &str, snapshot: Option<&str>| Deletion{
+            repo: RepoType::Dam, site: "gato".to_string(), path: path.to_string(), last_modified: None, snapshot: snapshot.map(|s| s.to_string()),
+        };
+        let mut deletions = vec![deletion("/gato/deleted.gif", Some("20180621")), deletion("/gato/failed.gif", None), deletion("/gato/never.gif", None)];
+        let earlier = vec![
+            ("20180620".to_string(), vec![Entry::skipped(&path(RepoType::Dam, "/gato/failed.gif"), "Skip error type")]),
+            ("20180619".to_string(), vec![
+                Entry::new(&path(RepoType::Dam, "/gato/failed.gif"), Action::Linked, Some(1)),
+                Entry::new(&path(RepoType::Dam, "/gato/deleted.gif"), Action::Exported, Some(1)),
+                Entry::new(&path(RepoType::Website, "/gato/never.gif"), Action::Exported, Some(1)),
+            ]),
+            ("20180618".to_string(), vec![Entry::new(&path(RepoType::Dam, "/gato/failed.gif"), Action::Exported, Some(1))]),
+        ];
+        last_copies(&mut deletions, earlier);
+        assert_eq!(deletions, vec![deletion("/gato/deleted.gif", Some("20180621")), deletion("/gato/failed.gif", Some("20180619")), deletion("/gato/never.gif", None)]);
+    }
+}
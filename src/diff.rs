@@ -0,0 +1,160 @@
+use std::io;
+use std::fs;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::SystemTime;
+use repos::RepoType;
+use manifest::{self, Entry};
+use snapshot::{self, Layout};
+use backup;
+use compress::Compression;
+
+/// Modified time, size, and compression of the file of an archived node
+type Stat = (Option<SystemTime>, u64, Compression);
+
+/// Archived nodes of a snapshot keyed by (repo, site) and then node path
+pub type Nodes = BTreeMap<(String, String), BTreeMap<String, Stat>>;
+
+/// Changes to the nodes of a site between two snapshots
+#[derive(Serialize, Debug, PartialEq, Default)]
+pub struct SiteDiff {
+    pub repo: String,
+    pub site: String,
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub modified: Vec<String>,
+}
+
+/// List the archived nodes of a snapshot. Those of the files layout are found
+/// by decoding filenames back into node paths whatever their compression, those
+/// of the objects layout are read from its manifest with the size of their export.
+pub fn nodes(archive_dir: &str, archive_ext: &str) -> io::Result<Nodes> {
+    match snapshot::layout(archive_dir, archive_ext)? {
+        Layout::Files => files(&snapshot::dir(archive_dir, archive_ext)),
+        Layout::Objects => stored(&snapshot::manifest_file(archive_dir, archive_ext)),
+    }
+}
+
+// nodes laid out by backup::archive_path and backup::backup_filename
+fn files(snapshot_dir: &str) -> io::Result<Nodes> {
+    let mut nodes = Nodes::new();
+    for repo in fs::read_dir(snapshot_dir)? {
+        let repo = repo?;
+        let repo_name = repo.file_name().to_string_lossy().into_owned();
+        if !repo.file_type()?.is_dir() || repo_name.parse::<RepoType>().is_err() {
+            continue;
+        }
+        for site in fs::read_dir(repo.path())? {
+            let site = site?;
+            if !site.file_type()?.is_dir() {
+                continue;
+            }
+            let site_name = site.file_name().to_string_lossy().into_owned();
+            let site_nodes = nodes.entry((repo_name.clone(), site_name.clone())).or_default();
+            for file in fs::read_dir(site.path())? {
+                let file = file?;
+                if let Some(path) = backup::node_path(&site_name, &file.file_name().to_string_lossy()) {
+                    let meta = file.metadata()?;
+                    site_nodes.insert(path, (meta.modified().ok(), meta.len(), Compression::of(&file.file_name().to_string_lossy())));
+                }
+            }
+        }
+    }
+    Ok(nodes)
+}
+
+/// Archived nodes recorded in a manifest with the size of their export
+pub fn stored(manifest_file: &str) -> io::Result<Nodes> {
+    let mut nodes = Nodes::new();
+    for entry in manifest::read_partial::<Entry>(manifest_file)?.into_iter().filter(|entry| entry.action.is_archived()) {
+        let stat = (entry.last_modified.map(SystemTime::from), entry.size.unwrap_or_default(), Compression::None);
+        nodes.entry((entry.repo.to_string(), entry.site)).or_default().insert(entry.path, stat);
+    }
+    Ok(nodes)
+}
+
+/// Compare the nodes of two snapshots. A node is modified when either its
+/// modified time or size differ, though the sizes of files compressed
+/// differently say nothing of their exports so only their times are compared.
+pub fn diff(from: &Nodes, to: &Nodes) -> Vec<SiteDiff> {
+    let empty = BTreeMap::new();
+    let sites: BTreeSet<&(String, String)> = from.keys().chain(to.keys()).collect();
+    let mut diffs = Vec::new();
+    for key in sites {
+        let from_nodes = from.get(key).unwrap_or(&empty);
+        let to_nodes = to.get(key).unwrap_or(&empty);
+        let mut site_diff = SiteDiff{ repo: key.0.clone(), site: key.1.clone(), ..Default::default() };
+        for (path, stat) in to_nodes {
+            match from_nodes.get(path) {
+                None => site_diff.added.push(path.clone()),
+                Some(from_stat) if is_modified(from_stat, stat) => site_diff.modified.push(path.clone()),
+                Some(_) => (),
+            }
+        }
+        site_diff.removed = from_nodes.keys().filter(|path| !to_nodes.contains_key(*path)).cloned().collect();
+        if !(site_diff.added.is_empty() && site_diff.removed.is_empty() && site_diff.modified.is_empty()) {
+            diffs.push(site_diff);
+        }
+    }
+    diffs
+}
+
+fn is_modified(from: &Stat, to: &Stat) -> bool {
+    from.0 != to.0 || (from.2 == to.2 && from.1 != to.1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backup::test_dir;
+    use nodes::PathInfo;
+    use filetime::{set_file_mtime, FileTime};
+
+    #[test]
+    fn test_diff_snapshots() {
+        let dir = test_dir("diff");
+        fs::create_dir_all(format!("{}/20180621/dam/gato", dir)).unwrap();
+        fs::create_dir_all(format!("{}/20180622/dam/gato", dir)).unwrap();
+        fs::create_dir_all(format!("{}/20180622/website/gato", dir)).unwrap();
+        fs::write(format!("{}/20180621/manifest.jsonl", dir), "").unwrap();
+        fs::write(format!("{}/20180621/dam/gato/same%2Egif.xml", dir), "same").unwrap();
+        fs::hard_link(format!("{}/20180621/dam/gato/same%2Egif.xml", dir), format!("{}/20180622/dam/gato/same%2Egif.xml", dir)).unwrap();
+        fs::write(format!("{}/20180621/dam/gato/removed%2Egif.xml", dir), "removed").unwrap();
+        fs::write(format!("{}/20180621/dam/gato/changed%2Egif.xml", dir), "changed").unwrap();
+        set_file_mtime(format!("{}/20180621/dam/gato/changed%2Egif.xml", dir), FileTime::from_unix_time(1529600000, 0)).unwrap();
+        fs::write(format!("{}/20180622/dam/gato/changed%2Egif.xml", dir), "changed").unwrap();
+        fs::write(format!("{}/20180622/website/gato/gato.xml", dir), "added").unwrap();
+        // recompressed without being modified
+        fs::write(format!("{}/20180621/dam/gato/packed%2Egif.xml", dir), "packed export").unwrap();
+        fs::write(format!("{}/20180622/dam/gato/packed%2Egif.xml.zst", dir), "packed").unwrap();
+        let mtime = FileTime::from_last_modification_time(&fs::metadata(format!("{}/20180621/dam/gato/packed%2Egif.xml", dir)).unwrap());
+        set_file_mtime(format!("{}/20180622/dam/gato/packed%2Egif.xml.zst", dir), mtime).unwrap();
+        let from = nodes(&dir, "20180621").unwrap();
+        let to = nodes(&dir, "20180622").unwrap();
+        assert_eq!(diff(&from, &to), vec![
+            SiteDiff{ repo: "dam".to_string(), site: "gato".to_string(), added: vec![], removed: vec!["/gato/removed.gif".to_string()], modified: vec!["/gato/changed.gif".to_string()] },
+            SiteDiff{ repo: "website".to_string(), site: "gato".to_string(), added: vec!["/gato".to_string()], removed: vec![], modified: vec![] },
+        ]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_diff_objects() {
+        let dir = test_dir("diff-objects");
+        fs::create_dir_all(format!("{}/20180621/dam/gato", dir)).unwrap();
+        fs::create_dir(format!("{}/20180622", dir)).unwrap();
+        fs::write(format!("{}/20180621/dam/gato/same%2Egif.xml", dir), "same").unwrap();
+        fs::write(format!("{}/20180621/dam/gato/changed%2Egif.xml", dir), "changed").unwrap();
+        snapshot::set_layout(&dir, "20180622", Layout::Objects).unwrap();
+        let mut manifest = manifest::Writer::create(&snapshot::manifest_file(&dir, "20180622")).unwrap();
+        for (path, size) in &[("/gato/same.gif", 4), ("/gato/changed.gif", 8)] {
+            let last_modified = backup::modified(&format!("{}/20180621/dam/gato/same%2Egif.xml", dir));
+            let path = PathInfo{ repo_type: RepoType::Dam, path: path.to_string(), last_modified };
+            manifest.write(&Entry::new(&path, manifest::Action::Linked, Some(*size)).with_sha256("aa".to_string())).unwrap();
+        }
+        manifest.finish().unwrap();
+        assert_eq!(diff(&nodes(&dir, "20180621").unwrap(), &nodes(&dir, "20180622").unwrap()), vec![
+            SiteDiff{ repo: "dam".to_string(), site: "gato".to_string(), added: vec![], removed: vec![], modified: vec!["/gato/changed.gif".to_string()] },
+        ]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,3 +1,6 @@
+// failure_derive expands Fail impls inside a const block.
+#![allow(non_local_definitions)]
+
 use std::time::Duration;
 use std::fmt::Display;
 use repos::RepoType;
@@ -5,9 +8,10 @@ use nodes::{self, Paths, PathInfo};
 use failure::{Error, err_msg};
 use percent_encoding::percent_decode;
 use regex::Regex;
-use reqwest::{blocking::Client, header, redirect, StatusCode};
+use reqwest::{blocking::{Body, Client}, header, redirect, StatusCode};
 use hyper::{Uri, http::uri::Authority};
 use std::io::Read;
+use std::str::FromStr;
 
 lazy_static!{
     static ref RE_JSESSIONID: Regex = Regex::new(r"^JSESSIONID=([A-F0-9]{32})[; ]").unwrap();
@@ -37,6 +41,20 @@ pub enum FetchError {
         error: String,
     },
 
+    // Request could not be sent or no response was received: connection
+    //   refused or reset, DNS failures, and timeouts
+    #[fail(display = "Transport error type: {}", error)]
+    Transport {
+        error: String,
+    },
+
+    // 200 response whose export is not the system view XML of the node
+    //   requested, such as an HTML error page or truncated XML
+    #[fail(display = "Invalid export: {}", error)]
+    Invalid {
+        error: String,
+    },
+
 // NOTE: All other errors should be logged and the request skipped.
 //   i.e. No immediate retry, backoff, or Blocking all future requests.
 //  Skip Request which gave reqwest::ClientBuilder::send()? request failure
@@ -47,6 +65,46 @@ pub enum FetchError {
     },
 }
 
+/// How an import handles nodes whose UUID already exists within the repo,
+/// matching javax.jcr.ImportUUIDBehavior.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum UuidBehavior {
+    CreateNew = 0,
+    RemoveExisting = 1,
+    ReplaceExisting = 2,
+    Throw = 3,
+}
+
+impl FromStr for UuidBehavior {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "new" => Ok(UuidBehavior::CreateNew),
+            "remove" => Ok(UuidBehavior::RemoveExisting),
+            "replace" => Ok(UuidBehavior::ReplaceExisting),
+            "throw" => Ok(UuidBehavior::Throw),
+            _ => Err(err_msg("Invalid uuid behavior")),
+        }
+    }
+}
+
+impl FetchError {
+    pub const KINDS: [&'static str; 6] = ["LostSession", "Blocking", "BackOff", "Transport", "Invalid", "Skip"];
+
+    /// Name of error variant
+    pub fn kind(&self) -> &'static str {
+        match self {
+            FetchError::LostSession{..} => "LostSession",
+            FetchError::Blocking{..} => "Blocking",
+            FetchError::BackOff{..} => "BackOff",
+            FetchError::Transport{..} => "Transport",
+            FetchError::Invalid{..} => "Invalid",
+            FetchError::Skip{..} => "Skip",
+        }
+    }
+}
+
 fn new_fetch_error<D: Display, T>(status_code: Option<StatusCode>, text: D) -> Result<T, FetchError> {
     if let Some(status_code) = status_code {
         if status_code.is_redirection() {
@@ -67,6 +125,10 @@ fn new_fetch_error_skip<D: Display, T>(text: D) -> Result<T, FetchError> {
     new_fetch_error(None, text)
 }
 
+fn new_fetch_error_transport<D: Display, T>(text: D) -> Result<T, FetchError> {
+    Err(FetchError::Transport{error: text.to_string()})
+}
+
 // authority is in the form Option<"user:password@host">
 fn split_authority(authority: Option<&Authority>) -> Result<(String, String), Error> {
     if let Some(auth) = authority.map(|a| a.as_str()) {
@@ -104,11 +166,11 @@ impl Fetch {
         // of at least 10 redirects before returning an error.
         // Also using ?ticket=<fake-token> query to force gato to return a JSESSIONID as that
         // seems to be required now.
-        let url = format!("{}/", self.url.to_string());
+        let url = format!("{}/", self.url);
         let resp = self.client.get(&url)
             .basic_auth(&*self.user, Some(&*self.password))
             .send()?;
-        if !resp.status().is_success() && !(resp.status() == StatusCode::FOUND) {
+        if !resp.status().is_success() && resp.status() != StatusCode::FOUND {
             Err(err_msg("Unable to retrieve a session. Invalid status."))
         } else {
             for (name, value) in resp.headers().iter() {
@@ -168,7 +230,7 @@ impl Fetch {
             .header(header::COOKIE, cookie_session)
             .header(header::ACCEPT, APPLICATION_JSON) //Accept(vec![qitem(mime::APPLICATION_JSON)]))
             .send()
-            .or_else(new_fetch_error_skip)?;
+            .or_else(new_fetch_error_transport)?;
         if resp.status().is_success() {
             Ok(nodes::build_paths(resp, repo_type, true).or_else(new_fetch_error_skip)?)
         } else {
@@ -178,18 +240,22 @@ impl Fetch {
 
     /// Fetch list of node paths for all sites within a repo and include the associated mgnl:lastModified properties found in nodes Metadata:
     ///   NOTE: Exclude 'mgnl:resources' from magnolia RESTful json responses as they include binary data we do NOT require.
-    /// For all paths within repo while NOT including mgnl:folders
+    /// For all paths within repo while NOT including mgnl:folders, or when a level is given
+    /// the paths reduced down to that level within the site (see nodes::reduce_paths)
     ///   curl -s -H 'Accept: application/json' '<url>/.rest/nodes/v1/<repo>/<site/path>?depth=999&excludeNodeTypes=mgnl:resource&includeMetadata=true'
-    pub fn paths(&self, path_info: &PathInfo) -> Result<Option<Paths>, FetchError> {
+    pub fn paths(&self, path_info: &PathInfo, level: Option<usize>) -> Result<Option<Paths>, FetchError> {
         let cookie_session = format!("JSESSIONID={}", self.session.as_ref().unwrap());
         let url = format!("{}/.rest/nodes/v1/{}{}?depth=999&excludeNodeTypes=mgnl:resource&includeMetadata=true", self.url, path_info.repo_type, path_info.path);
         let resp = self.client.get(&url)
             .header(header::COOKIE, cookie_session)
             .header(header::ACCEPT, APPLICATION_JSON) //Accept(vec![qitem(mime::APPLICATION_JSON)]))
             .send()
-            .or_else(new_fetch_error_skip)?;
+            .or_else(new_fetch_error_transport)?;
         if resp.status().is_success() {
-            Ok(nodes::build_paths(resp, path_info.repo_type, false).or_else(new_fetch_error_skip)?)
+            match level {
+                Some(level) => Ok(nodes::reduce_paths(resp, path_info.repo_type, level).or_else(new_fetch_error_skip)?),
+                None => Ok(nodes::build_paths(resp, path_info.repo_type, false).or_else(new_fetch_error_skip)?),
+            }
         } else {
             new_fetch_error(Some(resp.status()), "Unable to retrieve list of paths")
         }
@@ -205,7 +271,7 @@ impl Fetch {
         let resp = self.client.head(&url)
             .header(header::COOKIE, cookie_session)
             .send()
-            .or_else(new_fetch_error_skip)?;
+            .or_else(new_fetch_error_transport)?;
         if resp.status().is_success() {
             match resp.headers().get(header::CONTENT_LENGTH) {
                 Some(content_length) => match content_length.to_str() {
@@ -252,11 +318,42 @@ impl Fetch {
                 ("path", path_info.path.clone()),
             ])
             .send()
-            .or_else(new_fetch_error_skip)?;
+            .or_else(new_fetch_error_transport)?;
         if resp.status().is_success() {
             Ok(Box::new(resp))
         } else {
             new_fetch_error(Some(resp.status()), "Unable to retrieve export")
         }
     }
+
+    // Import an export previously saved by Fetch::export back under the parent node of path_info:
+    //   NOTE: An import jsp, the counterpart of export.jsp, is required as Magnolia put their import features
+    //     behind an interactive Vaadin framework. It imports the posted system view XML under the parent path.
+    //   curl -s --fail --cookie '<SessionID>' -H 'Content-Type: text/xml' --data-binary @<file> \
+    //     '<URL>/docroot/gato/import.jsp?repo=<repo>&path=</parent>&uuidBehavior=<0-3>'
+    pub fn import<R: Read + Send + 'static>(&self, path_info: &PathInfo, data: R, uuid_behavior: UuidBehavior) -> Result<(), FetchError> {
+        let cookie_session = format!("JSESSIONID={}", self.session.as_ref().unwrap());
+        let url = format!("{}/docroot/gato/import.jsp", &self.url);
+        let parent = match path_info.path.rfind('/') {
+            Some(0) | None => "/",
+            Some(end) => &path_info.path[..end],
+        };
+        let resp = self.client.post(&url)
+            .header(header::COOKIE, cookie_session)
+            .header(header::CONTENT_TYPE, TEXT_XML)
+            .header(header::REFERER, &url)
+            .query(&[
+                ("repo", path_info.repo_type.to_string()),
+                ("path", parent.to_string()),
+                ("uuidBehavior", (uuid_behavior as u8).to_string()),
+            ])
+            .body(Body::new(data))
+            .send()
+            .or_else(new_fetch_error_transport)?;
+        if resp.status().is_success() {
+            Ok(())
+        } else {
+            new_fetch_error(Some(resp.status()), "Unable to import")
+        }
+    }
 }
@@ -0,0 +1,130 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum State {
+    Closed,
+    /// No requests until the cool-down since opening has passed
+    Open(Instant),
+    /// Cooled down, the next request probes whether the backend recovered
+    HalfOpen,
+}
+
+/// Circuit breaker of a single backend
+#[derive(Debug)]
+struct Breaker {
+    failures: u32,
+    state: State,
+}
+
+/// Health of each backend in BACKUP_URLS, by index, shared by all threads.
+/// A backend's breaker opens after `failures` consecutive failures, or right
+/// away when tripped, and stays open for `cool_down` before being probed.
+#[derive(Debug)]
+pub struct Health {
+    breakers: Mutex<Vec<Breaker>>,
+    failures: u32,
+    cool_down: Duration,
+}
+
+impl Health {
+    pub fn new(backends: usize, failures: u32, cool_down: Duration) -> Health {
+        let breakers = (0..backends).map(|_| Breaker{ failures: 0, state: State::Closed }).collect();
+        Health{ breakers: Mutex::new(breakers), failures, cool_down }
+    }
+
+    /// Time left before an open backend may be probed, None when it
+    /// may be sent requests.
+    pub fn cool_down(&self, backend: usize) -> Option<Duration> {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = &mut breakers[backend];
+        match breaker.state {
+            State::Open(opened) => match self.cool_down.checked_sub(opened.elapsed()) {
+                Some(left) if left > Duration::from_secs(0) => Some(left),
+                _ => {
+                    breaker.state = State::HalfOpen;
+                    None
+                },
+            },
+            _ => None,
+        }
+    }
+
+    pub fn is_available(&self, backend: usize) -> bool {
+        self.cool_down(backend).is_none()
+    }
+
+    /// Backends that may be sent requests, in order starting from `first`
+    pub fn available(&self, first: usize) -> Vec<usize> {
+        let backends = self.breakers.lock().unwrap().len();
+        (0..backends).map(|n| (first + n) % backends).filter(|&backend| self.is_available(backend)).collect()
+    }
+
+    pub fn success(&self, backend: usize) {
+        let mut breakers = self.breakers.lock().unwrap();
+        breakers[backend].failures = 0;
+        breakers[backend].state = State::Closed;
+    }
+
+    /// Count a failed request, returning true when the backend's breaker is
+    /// open. A failed probe opens the breaker again.
+    pub fn failure(&self, backend: usize) -> bool {
+        let mut breakers = self.breakers.lock().unwrap();
+        let breaker = &mut breakers[backend];
+        breaker.failures += 1;
+        match breaker.state {
+            State::Open(_) => true,
+            State::HalfOpen => {
+                breaker.state = State::Open(Instant::now());
+                true
+            },
+            State::Closed if breaker.failures >= self.failures => {
+                breaker.state = State::Open(Instant::now());
+                true
+            },
+            State::Closed => false,
+        }
+    }
+
+    /// Open a backend's breaker regardless of its failure count
+    pub fn trip(&self, backend: usize) {
+        let mut breakers = self.breakers.lock().unwrap();
+        breakers[backend].failures += 1;
+        breakers[backend].state = State::Open(Instant::now());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_breaker_opens() {
+        let health = Health::new(3, 2, Duration::from_secs(60));
+        assert!(!health.failure(0));
+        health.success(0);
+        assert!(!health.failure(0));
+        assert!(health.failure(0));
+        health.trip(2);
+        assert!(health.cool_down(0).unwrap() <= Duration::from_secs(60));
+        assert_eq!(health.available(0), vec![1]);
+        assert_eq!(health.available(2), vec![1]);
+    }
+
+    #[test]
+    fn test_breaker_probe() {
+        let health = Health::new(2, 3, Duration::from_millis(20));
+        health.trip(0);
+        assert!(!health.is_available(0));
+        thread::sleep(Duration::from_millis(30));
+        assert_eq!(health.available(1), vec![1, 0]);
+        // a single failed probe opens it again
+        assert!(health.failure(0));
+        assert!(!health.is_available(0));
+        thread::sleep(Duration::from_millis(30));
+        assert!(health.is_available(0));
+        health.success(0);
+        assert!(!health.failure(0));
+    }
+}
@@ -0,0 +1,74 @@
+use std::sync::{Condvar, Mutex};
+
+/// Limit on how many of something may happen at once, None being no limit
+#[derive(Debug)]
+pub struct Limit {
+    max: Option<usize>,
+    count: Mutex<usize>,
+    released: Condvar,
+}
+
+/// Held while within a limit, which is released once dropped
+#[derive(Debug)]
+pub struct Permit<'a> {
+    limit: &'a Limit,
+}
+
+impl Limit {
+    pub fn new(max: Option<usize>) -> Limit {
+        Limit{ max, count: Mutex::new(0), released: Condvar::new() }
+    }
+
+    /// Wait until within the limit
+    pub fn acquire(&self) -> Permit<'_> {
+        let mut count = self.count.lock().unwrap();
+        if let Some(max) = self.max {
+            while *count >= max {
+                count = self.released.wait(count).unwrap();
+            }
+        }
+        *count += 1;
+        Permit{ limit: self }
+    }
+
+    pub fn count(&self) -> usize {
+        *self.count.lock().unwrap()
+    }
+}
+
+impl<'a> Drop for Permit<'a> {
+    fn drop(&mut self) {
+        *self.limit.count.lock().unwrap() -= 1;
+        self.limit.released.notify_one();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_limit() {
+        let limit = Limit::new(Some(2));
+        let most = AtomicUsize::new(0);
+        thread::scope(|scope| {
+            for _ in 0..6 {
+                scope.spawn(|| {
+                    let _permit = limit.acquire();
+                    most.fetch_max(limit.count(), Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(20));
+                });
+            }
+        });
+        assert_eq!(most.load(Ordering::SeqCst), 2);
+        assert_eq!(limit.count(), 0);
+        let unlimited = Limit::new(None);
+        let permits: Vec<Permit> = (0..10).map(|_| unlimited.acquire()).collect();
+        assert_eq!(unlimited.count(), 10);
+        drop(permits);
+        assert_eq!(unlimited.count(), 0);
+    }
+}
@@ -0,0 +1,130 @@
+use std::io;
+use std::fs::{self, File};
+use std::str::FromStr;
+use std::fmt::{self, Display};
+use std::os::unix::io::AsRawFd;
+use failure::{Error, err_msg};
+use libc;
+use backup;
+
+/// How an unchanged node is carried over from a previous snapshot
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Strategy {
+    /// Hard link sharing the previous snapshot's file
+    Hardlink,
+    /// Copy-on-write clone sharing the previous snapshot's blocks
+    /// until either is modified, on filesystems such as btrfs or XFS
+    Reflink,
+    Copy,
+}
+
+impl FromStr for Strategy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hardlink" => Ok(Strategy::Hardlink),
+            "reflink" => Ok(Strategy::Reflink),
+            "copy" => Ok(Strategy::Copy),
+            _ => Err(err_msg("Invalid value")),
+        }
+    }
+}
+
+impl Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Strategy::Hardlink => "hardlink",
+            Strategy::Reflink => "reflink",
+            Strategy::Copy => "copy",
+        })
+    }
+}
+
+/// Carry file from over to file to, which must not exist yet, per strategy.
+/// Falls back to a copy when from cannot be linked to, such as across
+/// filesystems or once it has the most links allowed, or cloned, returning
+/// the strategy that was actually used.
+pub fn carry(strategy: Strategy, from: &str, to: &str) -> io::Result<Strategy> {
+    let result = match strategy {
+        Strategy::Hardlink => fs::hard_link(from, to),
+        Strategy::Reflink => reflink(from, to),
+        Strategy::Copy => return copy(from, to).map(|_| Strategy::Copy),
+    };
+    match result {
+        Ok(()) => Ok(strategy),
+        Err(ref e) if falls_back(strategy, e) => copy(from, to).map(|_| Strategy::Copy),
+        Err(e) => Err(e),
+    }
+}
+
+// errors of a link or clone that a copy gets around
+fn falls_back(strategy: Strategy, error: &io::Error) -> bool {
+    match error.raw_os_error() {
+        Some(libc::EXDEV) | Some(libc::EMLINK) => true,
+        // filesystem cannot clone
+        Some(libc::EOPNOTSUPP) | Some(libc::EINVAL) | Some(libc::ENOTTY) => strategy == Strategy::Reflink,
+        _ => false,
+    }
+}
+
+// clone through a temporary file so a failed clone leaves nothing behind
+fn reflink(from: &str, to: &str) -> io::Result<()> {
+    let source = File::open(from)?;
+    let size = source.metadata()?.len();
+    backup::write_file_with(to, None, |temp| {
+        // SAFETY: both file descriptors are open for the duration of the call
+        if unsafe { libc::ioctl(temp.as_raw_fd(), libc::FICLONE, source.as_raw_fd()) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(size)
+    }).map(|_| ())
+}
+
+fn copy(from: &str, to: &str) -> io::Result<u64> {
+    let mut source = File::open(from)?;
+    backup::write_file_with(to, None, |temp| io::copy(&mut source, temp))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+    use backup::test_dir;
+
+    #[test]
+    fn test_carry() {
+        let dir = test_dir("carry");
+        let from = format!("{}/from.xml", dir);
+        fs::write(&from, "<sv:node/>").unwrap();
+        for &strategy in &[Strategy::Hardlink, Strategy::Reflink, Strategy::Copy] {
+            assert_eq!(strategy.to_string().parse::<Strategy>().unwrap(), strategy);
+            let to = format!("{}/{}.xml", dir, strategy);
+            let used = carry(strategy, &from, &to).unwrap();
+            assert_eq!(fs::read_to_string(&to).unwrap(), "<sv:node/>");
+            match strategy {
+                Strategy::Hardlink => assert_eq!(used, Strategy::Hardlink),
+                // clones are only supported by some filesystems
+                Strategy::Reflink => assert!(used == Strategy::Reflink || used == Strategy::Copy),
+                Strategy::Copy => assert_eq!(used, Strategy::Copy),
+            }
+            assert!(fs::metadata(backup::temp_filename(&to)).is_err());
+        }
+        assert_eq!(fs::metadata(&from).unwrap().nlink(), 2);
+        assert!(carry(Strategy::Copy, &format!("{}/missing.xml", dir), &format!("{}/missing-copy.xml", dir)).is_err());
+        assert!("symlink".parse::<Strategy>().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_falls_back() {
+        let error = |errno| io::Error::from_raw_os_error(errno);
+        assert!(falls_back(Strategy::Hardlink, &error(libc::EXDEV)));
+        assert!(falls_back(Strategy::Hardlink, &error(libc::EMLINK)));
+        assert!(!falls_back(Strategy::Hardlink, &error(libc::EOPNOTSUPP)));
+        assert!(!falls_back(Strategy::Hardlink, &error(libc::EACCES)));
+        assert!(falls_back(Strategy::Reflink, &error(libc::EXDEV)));
+        assert!(falls_back(Strategy::Reflink, &error(libc::EOPNOTSUPP)));
+        assert!(!falls_back(Strategy::Reflink, &io::Error::other("not an os error")));
+    }
+}
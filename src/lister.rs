@@ -0,0 +1,199 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Instant;
+use crossbeam_channel::{Receiver, Sender};
+use fetch::{Fetch, FetchError};
+use nodes::PathInfo;
+use repos::Repo;
+use manifest::Entry;
+use deletions::Listing;
+use metrics::Metrics;
+use health::Health;
+use retry::Attempts;
+use queue::Queue;
+use resume::Remaining;
+use logging::{self, Level};
+use shutdown;
+
+/// Outcome of a listing request
+pub enum Listed<T> {
+    Done(T),
+    /// Ran out of attempts
+    Failed,
+    /// No backend is available
+    Abort,
+    /// Shutdown was requested before it was listed
+    Interrupted,
+}
+
+/// Connection used to list sites and paths which fails over to the next
+/// available backend of BACKUP_URLS when its backend becomes unhealthy.
+pub struct Lister<'a> {
+    backup_urls: &'a [String],
+    backend: usize,
+    log: logging::Context,
+    magnolia: Option<Fetch>,
+    metrics: Metrics,
+    health: Arc<Health>,
+}
+
+impl<'a> Lister<'a> {
+    /// Lister starting with backend, the index of its url in BACKUP_URLS
+    pub fn new(backup_urls: &'a [String], backend: usize, log: logging::Context, health: Arc<Health>) -> Lister<'a> {
+        Lister{ backup_urls, backend, log, magnolia: None, metrics: Metrics::new(), health }
+    }
+
+    /// Connect to the first available backend starting with the current one
+    fn connect(&mut self) -> bool {
+        for backend in self.health.available(self.backend) {
+            let url = &self.backup_urls[backend];
+            let log = self.log.clone().backend(url);
+            match Fetch::new(url) {
+                Ok(magnolia) => {
+                    if backend != self.backend {
+                        warn!(log, "Listing from backend {}", logging::host(url).unwrap_or_default());
+                    }
+                    self.backend = backend;
+                    self.log = log;
+                    self.magnolia = Some(magnolia);
+                    return true;
+                },
+                Err(e) => {
+                    error!(log, "Unable to connect, {}", e);
+                    self.health.trip(backend);
+                },
+            }
+        }
+        false
+    }
+
+    /// Make a listing request of a repo or site, logged as what, retrying
+    /// failed requests per attempts and failing over between backends.
+    pub fn list<T, F>(&mut self, attempts: &mut Attempts, what: &str, site: Option<&PathInfo>, request_kind: &'static str, request: F) -> Listed<T>
+        where F: Fn(&Fetch) -> Result<T, FetchError>
+    {
+        loop {
+            if shutdown::is_requested() {
+                return Listed::Interrupted;
+            }
+            if (self.magnolia.is_none() || !self.health.is_available(self.backend)) && !self.connect() {
+                error!(self.log, "No backends available to list {}", what);
+                return Listed::Abort;
+            }
+            let host = logging::host(&self.backup_urls[self.backend]).unwrap_or_default();
+            let magnolia = self.magnolia.as_mut().unwrap();
+            let start = Instant::now();
+            let result = request(magnolia);
+            self.metrics.request(&host, request_kind, start.elapsed());
+            let error = match result {
+                Ok(listed) => {
+                    self.health.success(self.backend);
+                    return Listed::Done(listed);
+                },
+                Err(error) => error,
+            };
+            self.metrics.fetch_error(&host, &error);
+            let unhealthy = match error {
+                FetchError::Blocking{..} => {
+                    self.health.trip(self.backend);
+                    true
+                },
+                FetchError::BackOff{..} | FetchError::Transport{..} => self.health.failure(self.backend),
+                _ => false,
+            };
+            let delay = if unhealthy { None } else { attempts.retry(&error) };
+            let level = if delay.is_some() || unhealthy { Level::Warn } else { Level::Error };
+            let event = match site {
+                Some(site) => self.log.path(site),
+                None => self.log.event(),
+            };
+            event.fetch_error(&error).session(&magnolia.session).log(level, format_args!("{}, session: {:?}, {}", what, magnolia.session, error));
+            if unhealthy {
+                warn!(self.log, "Backend {} is unhealthy", host);
+                self.magnolia = None;
+                if attempts.requeue() {
+                    continue;
+                }
+                return Listed::Failed;
+            }
+            if let Some(delay) = delay {
+                if !shutdown::sleep(delay) {
+                    return Listed::Interrupted;
+                }
+            }
+            // Reset connection and renew session as magnolia cannot
+            // recover a persistent connection after a server error
+            if let FetchError::LostSession{..} | FetchError::BackOff{..} | FetchError::Transport{..} = error {
+                self.metrics.session_renewal(&host);
+                if let Err(e) = magnolia.new_client() {
+                    error!(self.log, "{}, {}", what, e);
+                    self.health.trip(self.backend);
+                    self.magnolia = None;
+                    if attempts.requeue() {
+                        continue;
+                    }
+                    return Listed::Failed;
+                }
+            }
+            if delay.is_none() {
+                return Listed::Failed;
+            }
+        }
+    }
+
+    /// List the paths of each site received and dispatch them to the workers
+    /// until there are no more sites or the run is aborted, returning what was
+    /// listed along with any errors, the metrics of the requests made, and the
+    /// sites and paths left over by a shutdown.
+    pub fn run(mut self, sites: Receiver<(&Repo, PathInfo)>, queue: &Queue, dispatch: &Sender<PathInfo>, results: &Sender<Entry>, aborted: &AtomicBool) -> (Listing, Vec<String>, Metrics, Remaining) {
+        let mut listing = Listing::new();
+        let mut errors = Vec::new();
+        let mut remaining = Remaining::new();
+        for (repo, site) in sites {
+            if aborted.load(Ordering::Relaxed) {
+                break;
+            }
+            let mut attempts = Attempts::new(repo.retry);
+            match self.list(&mut attempts, &site.path, Some(&site), "paths", |magnolia| magnolia.paths(&site, repo.level)) {
+                Listed::Done(Some(paths)) => {
+                    for path in paths {
+                        listing.path(&path);
+                        self::dispatch(&self.log, queue, dispatch, results, path, &mut remaining);
+                    }
+                },
+                Listed::Done(None) => info!(self.log.path(&site), "No paths for site {}", &site.path),
+                Listed::Failed => {
+                    listing.failed_site(&site);
+                    errors.push(format!("Unable to list {} {}", repo.repo_type, &site.path));
+                },
+                Listed::Abort => {
+                    aborted.store(true, Ordering::Relaxed);
+                    break;
+                },
+                Listed::Interrupted => remaining.sites.push(site),
+            }
+        }
+        (listing, errors, self.metrics, remaining)
+    }
+
+    pub fn finish(self) -> Metrics {
+        self.metrics
+    }
+}
+
+/// Dispatch a path to the workers, leaving it for the next run when a shutdown
+/// is requested or recording it as failed when no worker is left to take it up.
+pub fn dispatch(log: &logging::Context, queue: &Queue, dispatch: &Sender<PathInfo>, results: &Sender<Entry>, path: PathInfo, remaining: &mut Remaining) {
+    let path = match queue.dispatch(dispatch, path) {
+        Ok(()) => return,
+        Err(path) => path,
+    };
+    if shutdown::is_requested() {
+        remaining.paths.push(path);
+        return;
+    }
+    error!(log.path(&path), "No workers available for {} {}", path.repo_type, &path.path);
+    if let Err(e) = results.send(Entry::failed(&path, "No workers available")) {
+        error!(log.path(&path), "{}, {}", &path.path, e);
+    }
+}
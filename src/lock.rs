@@ -0,0 +1,129 @@
+// failure_derive expands Fail impls inside a const block.
+#![allow(non_local_definitions)]
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::fs::{DirBuilder, File, OpenOptions, TryLockError};
+use std::fmt;
+use std::process;
+use serde_json;
+use chrono::{DateTime, Local};
+use libc;
+
+/// Lock file within ARCHIVE_DIR, hidden so it is never mistaken for a snapshot
+pub const LOCK: &str = ".pagers.lock";
+
+/// Run holding the lock, written into the lock file while it is held
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Holder {
+    pub pid: u32,
+    pub host: String,
+    pub started: DateTime<Local>,
+}
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "pid {} on {} since {}", self.pid, self.host, self.started.to_rfc3339())
+    }
+}
+
+#[derive(Debug, Fail)]
+pub enum LockError {
+    #[fail(display = "Another run is active in {}, {}", dir, holder)]
+    Held {
+        dir: String,
+        holder: String,
+    },
+
+    #[fail(display = "Unable to lock {}, {}", file, error)]
+    Io {
+        file: String,
+        error: io::Error,
+    },
+}
+
+/// Exclusive advisory lock on an archive directory, released once dropped
+/// or when the process exits, however it exits.
+#[derive(Debug)]
+pub struct Lock {
+    file: File,
+    /// Left in the lock file by a run that did not release it
+    pub stale: Option<Holder>,
+}
+
+fn hostname() -> String {
+    let mut name = [0u8; 256];
+    let result = unsafe { libc::gethostname(name.as_mut_ptr() as *mut libc::c_char, name.len()) };
+    if result != 0 {
+        return "unknown".to_string();
+    }
+    let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+    String::from_utf8_lossy(&name[..len]).into_owned()
+}
+
+fn lock_file(archive_dir: &str) -> String {
+    format!("{}/{}", archive_dir, LOCK)
+}
+
+fn read_holder(file: &mut File) -> Option<Holder> {
+    let mut text = String::new();
+    file.seek(SeekFrom::Start(0)).and_then(|_| file.read_to_string(&mut text)).ok()?;
+    serde_json::from_str(&text).ok()
+}
+
+/// Lock archive dir for this run, failing right away if another run holds it.
+pub fn acquire(archive_dir: &str) -> Result<Lock, LockError> {
+    let file_name = lock_file(archive_dir);
+    let io_error = |error| LockError::Io{ file: file_name.clone(), error };
+    DirBuilder::new().recursive(true).create(archive_dir).map_err(io_error)?;
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&file_name).map_err(io_error)?;
+    match file.try_lock() {
+        Ok(()) => (),
+        Err(TryLockError::WouldBlock) => {
+            let holder = read_holder(&mut file).map(|holder| holder.to_string()).unwrap_or_else(|| "starting up".to_string());
+            return Err(LockError::Held{ dir: archive_dir.to_string(), holder });
+        },
+        Err(TryLockError::Error(error)) => return Err(io_error(error)),
+    }
+    let stale = read_holder(&mut file);
+    let holder = Holder{ pid: process::id(), host: hostname(), started: Local::now() };
+    file.set_len(0)
+        .and_then(|_| file.seek(SeekFrom::Start(0)))
+        .and_then(|_| file.write_all(serde_json::to_string(&holder)?.as_bytes()))
+        .and_then(|_| file.sync_all())
+        .map_err(io_error)?;
+    Ok(Lock{ file, stale })
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // an empty lock file tells the next run the lock was released
+        let _ = self.file.set_len(0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use backup::test_dir;
+
+    #[test]
+    fn test_lock() {
+        let dir = test_dir("lock");
+        let lock = acquire(&dir).unwrap();
+        assert!(lock.stale.is_none());
+        match acquire(&dir) {
+            Err(LockError::Held{ holder, .. }) => assert!(holder.starts_with(&format!("pid {} on ", process::id()))),
+            result => panic!("expected lock to be held, {:?}", result),
+        }
+        drop(lock);
+        assert_eq!(fs::read_to_string(lock_file(&dir)).unwrap(), "");
+        // left behind by a run that was killed
+        let holder = Holder{ pid: 42, host: "backup1".to_string(), started: "2018-06-22T01:00:00-05:00".parse().unwrap() };
+        fs::write(lock_file(&dir), serde_json::to_string(&holder).unwrap()).unwrap();
+        let lock = acquire(&dir).unwrap();
+        assert_eq!(lock.stale, Some(holder));
+        drop(lock);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
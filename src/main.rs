@@ -11,21 +11,75 @@ extern crate filetime;
 extern crate crossbeam_channel;
 extern crate reqwest;
 extern crate hyper;
+extern crate rand;
+extern crate signal_hook;
+extern crate libc;
+extern crate sha2;
+extern crate hmac;
+extern crate quick_xml;
+extern crate flate2;
+extern crate zstd;
+extern crate tar;
 
+#[macro_use] pub mod logging;
 pub mod repos;
 pub mod nodes;
 pub mod fetch;
 pub mod backup;
+pub mod snapshot;
+pub mod manifest;
+pub mod prune;
+pub mod restore;
+pub mod deletions;
+pub mod diff;
+pub mod summary;
+pub mod metrics;
+pub mod retry;
+pub mod health;
+pub mod queue;
+pub mod worker;
+pub mod lister;
+pub mod limit;
+pub mod shutdown;
+pub mod resume;
+pub mod lock;
+pub mod verify;
+pub mod validate;
+pub mod compress;
+pub mod objects;
+pub mod link;
+pub mod pack;
+pub mod storage;
+pub mod s3;
 
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use crossbeam_channel as channel;
 use std::env;
 use std::io;
-use fetch::{Fetch, FetchError};
-use std::fs::{self, DirBuilder, File};
-use chrono::DateTime;
-use filetime::{set_file_times, FileTime};
+use std::path::Path;
+use std::process;
+use std::collections::HashMap;
+use fetch::{Fetch, FetchError, UuidBehavior};
+use failure::Error;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use repos::Repo;
+use health::Health;
+use queue::Queue;
+use worker::Worker;
+use lister::{Lister, Listed};
+use limit::Limit;
+use metrics::Metrics;
+use manifest::Entry;
+use resume::Remaining;
+use compress::Compression;
+use link::Strategy;
+use storage::{Filesystem, Storage};
+use s3::S3;
+use snapshot::Layout;
+use summary::{Status, Summary};
+use std::fs::{self, DirBuilder};
 
 // BACKUP_URLS is a comma delimited list of the cluster
 // used to backup the data.
@@ -39,6 +93,18 @@ lazy_static!{
     };
 }
 
+// REPOS environment variable is a JSON list of the repos to backup,
+// each optionally limited to a whitelist of sites. Defaults to the dam.
+// [{"dam":["dam1"]},"website","config","users",...]
+lazy_static!{
+    static ref REPOS: Vec<Repo> = {
+        match env::var("REPOS") {
+            Ok(repos) => repos::new(&repos).unwrap_or_else(|e| usage(&format!("Invalid list of repos, {}", e))),
+            Err(_) => vec![Repo::new(repos::RepoType::Dam, None)],
+        }
+    };
+}
+
 // ARCHIVE_EXT environment variable is generally used to
 // date the backups for the current day like:
 // <ARCHIVE_DIR>/2018-06-22-Fri/<REPO>/<SITE>/<PercentEncoded(PATH)>
@@ -51,14 +117,21 @@ lazy_static!{
     };
 }
 
-// PREVIOUS_EXT environment variable is generally used to
-// indicate the date used for the backups the of previous day like:
+// PREVIOUS_EXT optional environment variable may be used to
+// force the snapshot unchanged nodes are hard linked from like:
 // <ARCHIVE_DIR>/2018-06-21-Thu/<REPO>/<SITE>/<PercentEncode(PATH)>
+// Otherwise the most recent snapshots in ARCHIVE_DIR are used.
 lazy_static!{
-    static ref PREVIOUS_EXT: String = {
-        match env::var("PREVIOUS_EXT") {
-            Ok(ext) => ext,
-            Err(_) => panic!("Require an archive extension"),
+    static ref PREVIOUS_EXT: Option<String> = env::var("PREVIOUS_EXT").ok();
+}
+
+// PREVIOUS_COUNT environment variable is the number of most
+// recent snapshots searched for an unchanged node. Defaults to 3.
+lazy_static!{
+    static ref PREVIOUS_COUNT: usize = {
+        match env::var("PREVIOUS_COUNT") {
+            Ok(count) => count.parse().unwrap_or_else(|e| usage(&format!("Invalid previous count, {}", e))),
+            Err(_) => 3,
         }
     };
 }
@@ -74,164 +147,917 @@ lazy_static!{
     };
 }
 
-fn run(backup_urls: &Vec<String>, archive_dir: &'static str, archive_ext: &'static str, previous_ext: &'static str) {
-    let primary_url = backup_urls.first().unwrap().clone();
-    let (s, r) = channel::bounded(backup_urls.len());
-    for (thread_n, url) in backup_urls.iter().enumerate() {
-        let thread_r = r.clone();
-        let thread_url = url.clone();
-        thread::spawn(move || {
-            let mut magnolia = Fetch::new(&thread_url).unwrap();
-            for path in thread_r {
-                // if previous file exists and has matching modified times then hard link, else create a new entry
-                let previous_file = format!("{}/{}", backup::archive_path(archive_dir, previous_ext, &path), backup::backup_filename(&path));
-                let archive_file = format!("{}/{}", backup::archive_path(archive_dir, archive_ext, &path), backup::backup_filename(&path));
-                if let Ok(p_meta) = fs::metadata(&previous_file) {
-                    if let Ok(p_modified) = p_meta.modified() {
-                        if Some(DateTime::from(p_modified)) == path.last_modified {
-                            if let Err(e) = fs::hard_link(&previous_file, &archive_file) {
-                                println!("ERROR[{}]: {}, {}", thread_n, &path.path, e);
-                            }
-                            let timestamp = FileTime::from_unix_time(path.last_modified.unwrap().timestamp(), path.last_modified.unwrap().timestamp_subsec_nanos());
-                            if let Err(e) = set_file_times(&archive_file, timestamp, timestamp) {
-                                println!("ERROR[{}]: {}, {}", thread_n, &path.path, e);
-                            }
-                            continue;
-                        }
-                    }
+// KEEP_DAILY, KEEP_WEEKLY, and KEEP_MONTHLY environment variables
+// set how many daily, weekly, and monthly snapshots are retained
+// when pruning. Defaults to 7 daily, 4 weekly, and 12 monthly.
+lazy_static!{
+    static ref RETENTION: prune::Policy = {
+        let keep = |name: &str, default: usize| match env::var(name) {
+            Ok(count) => count.parse().unwrap_or_else(|e| usage(&format!("Invalid {}, {}", name, e))),
+            Err(_) => default,
+        };
+        prune::Policy{
+            daily: keep("KEEP_DAILY", 7),
+            weekly: keep("KEEP_WEEKLY", 4),
+            monthly: keep("KEEP_MONTHLY", 12),
+        }
+    };
+}
+
+// LOG_FORMAT environment variable set to json emits log events
+// as JSON lines, otherwise the human readable format is used.
+lazy_static!{
+    static ref LOG_JSON: bool = {
+        match env::var("LOG_FORMAT") {
+            Ok(format) => match format.as_str() {
+                "json" => true,
+                "text" => false,
+                format => usage(&format!("Invalid log format, {}", format)),
+            },
+            Err(_) => false,
+        }
+    };
+}
+
+// METRICS_FILE optional environment variable is the node_exporter
+// textfile collector file metrics of each backup run are written to like:
+// /var/lib/node_exporter/textfile_collector/pagers.prom
+lazy_static!{
+    static ref METRICS_FILE: Option<String> = env::var("METRICS_FILE").ok();
+}
+
+// BREAKER_FAILURES environment variable is the number of consecutive failed
+// requests after which a backend is considered unhealthy and given no work
+// until BREAKER_COOL_DOWN seconds have passed. Defaults to 3 and 300.
+lazy_static!{
+    static ref BREAKER_FAILURES: u32 = {
+        match env::var("BREAKER_FAILURES") {
+            Ok(failures) => failures.parse().unwrap_or_else(|e| usage(&format!("Invalid breaker failures, {}", e))),
+            Err(_) => 3,
+        }
+    };
+    static ref BREAKER_COOL_DOWN: Duration = {
+        match env::var("BREAKER_COOL_DOWN") {
+            Ok(secs) => Duration::from_secs(secs.parse().unwrap_or_else(|e| usage(&format!("Invalid breaker cool down, {}", e)))),
+            Err(_) => Duration::from_secs(300),
+        }
+    };
+}
+
+// WORKERS environment variable is the number of workers, each with its own
+// session, exporting from each backend, or a comma delimited list of the
+// number of workers for each of BACKUP_URLS. Defaults to 1.
+lazy_static!{
+    static ref WORKERS: Vec<usize> = {
+        match env::var("WORKERS") {
+            Ok(workers) => {
+                let workers: Vec<usize> = workers.split(",")
+                    .map(|n| n.parse().unwrap_or_else(|e| usage(&format!("Invalid number of workers, {}", e))))
+                    .collect();
+                match workers.len() {
+                    1 => vec![workers[0]; BACKUP_URLS.len()],
+                    n if n == BACKUP_URLS.len() => workers,
+                    n => usage(&format!("Invalid number of workers, {} given for {} backup URLs", n, BACKUP_URLS.len())),
+                }
+            },
+            Err(_) => vec![1; BACKUP_URLS.len()],
+        }
+    };
+}
+
+// MAX_EXPORTS optional environment variable limits the number of exports
+// at once across all backends, otherwise each worker may export at once.
+lazy_static!{
+    static ref MAX_EXPORTS: Option<usize> = env::var("MAX_EXPORTS").ok()
+        .map(|max| match max.parse() {
+            Ok(0) => usage("Invalid max exports, must be at least 1"),
+            Ok(max) => max,
+            Err(e) => usage(&format!("Invalid max exports, {}", e)),
+        });
+}
+
+// COMPRESSION environment variable compresses newly exported files with
+// gzip or zstd, adding .gz or .zst to their filenames. Defaults to none.
+lazy_static!{
+    static ref COMPRESSION: Compression = {
+        match env::var("COMPRESSION") {
+            Ok(compression) => compression.parse().unwrap_or_else(|e| usage(&format!("Invalid compression, {}", e))),
+            Err(_) => Compression::None,
+        }
+    };
+}
+
+// LAYOUT environment variable set to objects stores each export once by its
+// SHA-256 in <ARCHIVE_DIR>/.objects, snapshots then only holding a manifest of
+// the objects they use, rather than as files hard linked between snapshots.
+// Defaults to files.
+lazy_static!{
+    static ref LAYOUT: Layout = {
+        match env::var("LAYOUT") {
+            Ok(layout) => layout.parse().unwrap_or_else(|e| usage(&format!("Invalid layout, {}", e))),
+            Err(_) => Layout::Files,
+        }
+    };
+}
+
+// LINK environment variable is how unchanged nodes of the files layout are
+// carried over from a previous snapshot: hardlink, reflink (a copy-on-write
+// clone), or copy. Either link falls back to a copy when it cannot be made,
+// such as across filesystems. Defaults to hardlink.
+lazy_static!{
+    static ref LINK: Strategy = {
+        match env::var("LINK") {
+            Ok(link) => link.parse().unwrap_or_else(|e| usage(&format!("Invalid link, {}", e))),
+            Err(_) => Strategy::Hardlink,
+        }
+    };
+}
+
+// S3_URL optional environment variable archives the exports of the files
+// layout in a bucket of S3-compatible object storage, such as MinIO, rather
+// than in ARCHIVE_DIR, which still holds the manifests of the snapshots.
+// https://<access key>:<secret key>@host:port/<bucket>[/<prefix>]
+lazy_static!{
+    static ref S3_URL: Option<String> = env::var("S3_URL").ok();
+}
+
+// S3_REGION environment variable is the region requests to S3_URL are signed
+// for. Defaults to us-east-1, which MinIO accepts by default.
+lazy_static!{
+    static ref S3_REGION: String = env::var("S3_REGION").unwrap_or_else(|_| "us-east-1".to_string());
+}
+
+// LISTERS environment variable is the number of sites whose paths are
+// listed at once, separate from the workers exporting them. Defaults to 1.
+lazy_static!{
+    static ref LISTERS: usize = {
+        match env::var("LISTERS") {
+            Ok(listers) => match listers.parse() {
+                Ok(0) => usage("Invalid number of listers, must be at least 1"),
+                Ok(listers) => listers,
+                Err(e) => usage(&format!("Invalid number of listers, {}", e)),
+            },
+            Err(_) => 1,
+        }
+    };
+}
+
+/// How work is spread across BACKUP_URLS
+struct Pool {
+    /// Workers exporting from each backend
+    workers: Vec<usize>,
+    /// Most exports at once across all backends
+    exports: Option<usize>,
+    /// Sites whose paths are listed at once
+    listers: usize,
+    breaker_failures: u32,
+    breaker_cool_down: Duration,
+}
+
+// Exit code for invalid command line usage, see summary::Status for others.
+const EXIT_USAGE: i32 = 64;
+
+// Exit code when another run holds the lock on ARCHIVE_DIR.
+const EXIT_LOCKED: i32 = 75;
+
+const USAGE: &str = "Usage: pagers [backup | prune [--dry-run] | restore <snapshot> <repo> <site|path> [--uuid=new|remove|replace|throw] [--dry-run] | diff <snapshot> <snapshot> [--json] | verify <snapshot> | pack <snapshot> <file> [<repo> [<site>]] | unpack <file>]";
+
+// Report invalid usage along with how to use pagers and exit
+fn usage(message: &str) -> ! {
+    eprintln!("{}", message);
+    println!("{}", USAGE);
+    process::exit(EXIT_USAGE);
+}
+
+// Compare what was listed against the manifest of the previous snapshot
+// and record any nodes that have since been deleted.
+fn record_deletions(log: &logging::Context, repos: &[Repo], listing: &deletions::Listing, archive_dir: &str, archive_ext: &str, previous_ext: &str) {
+    let previous = match manifest::read(&snapshot::manifest_file(archive_dir, previous_ext)) {
+        Ok(previous) => previous,
+        Err(e) => {
+            warn!(log, "Unable to read manifest of previous snapshot {}, {}", previous_ext, e);
+            return;
+        },
+    };
+    let mut deletions = listing.deletions(repos, previous, previous_ext);
+    // nodes the previous snapshot had no copy of may still be held by an earlier one
+    let earlier = snapshot::previous(archive_dir, previous_ext, usize::MAX).unwrap_or_else(|e| {
+        warn!(log, "Unable to find snapshots before {}, {}", previous_ext, e);
+        Vec::new()
+    });
+    deletions::last_copies(&mut deletions, earlier.into_iter().filter_map(|ext| {
+        match manifest::read(&snapshot::manifest_file(archive_dir, &ext)) {
+            Ok(entries) => Some((ext, entries)),
+            Err(e) => {
+                warn!(log, "Unable to read manifest of snapshot {}, {}", ext, e);
+                None
+            },
+        }
+    }));
+    let result = manifest::Writer::create(&snapshot::deletions_file(archive_dir, archive_ext)).and_then(|mut file| {
+        for deletion in &deletions {
+            info!(log, "Deleted {} {} last modified {:?}, last copy in {:?}", deletion.repo, deletion.path, deletion.last_modified, deletion.snapshot);
+            file.write(deletion)?;
+        }
+        file.finish()
+    });
+    match result {
+        Ok(count) => info!(log, "Recorded {} deletions since snapshot {}", count, previous_ext),
+        Err(e) => error!(log, "NOT able to record deletions, {}", e),
+    }
+}
+
+// Storage of the exports of the files layout, S3_URL if given or else ARCHIVE_DIR
+fn storage(archive_dir: &str) -> Result<Box<dyn Storage>, Error> {
+    Ok(match *S3_URL {
+        Some(ref url) => Box::new(S3::new(url, &S3_REGION)?),
+        None => Box::new(Filesystem::new(archive_dir, *LINK)),
+    })
+}
+
+// Storage of the exports of the files layout, exiting with usage when S3_URL is invalid
+fn storage_or_usage(archive_dir: &str) -> Box<dyn Storage> {
+    storage(archive_dir).unwrap_or_else(|e| usage(&format!("Invalid S3_URL, {}", e)))
+}
+
+fn run(backup_urls: &[String], repos: &[Repo], archive_dir: &'static str, archive_ext: &'static str, previous_exts: Vec<String>, metrics_file: Option<&str>, pool: &Pool) -> Status {
+    let start = Instant::now();
+    let log = logging::Context::new("m").run(archive_ext);
+    if *LAYOUT == Layout::Objects && S3_URL.is_some() {
+        error!(log, "NOT able to store objects in S3, the objects layout is kept in ARCHIVE_DIR");
+        return Status::Failed;
+    }
+    let storage = storage_or_usage(archive_dir);
+    // a snapshot keeps the layout it was started with
+    let manifest_file = snapshot::manifest_file(archive_dir, archive_ext);
+    let in_place = fs::metadata(&manifest_file).is_ok();
+    if in_place {
+        match snapshot::layout(archive_dir, archive_ext) {
+            Ok(layout) if layout == *LAYOUT => (),
+            Ok(layout) => {
+                error!(log, "NOT able to resume snapshot {} taken with the {} layout as {}", archive_ext, layout, *LAYOUT);
+                return Status::Failed;
+            },
+            Err(e) => {
+                error!(log, "NOT able to read layout of snapshot {}, {}", archive_ext, e);
+                return Status::Failed;
+            },
+        }
+    }
+    // snapshot is no longer complete once modified
+    if let Err(e) = DirBuilder::new().recursive(true).create(snapshot::dir(archive_dir, archive_ext)).and_then(|_| snapshot::incomplete(archive_dir, archive_ext)) {
+        error!(log, "NOT able to prepare snapshot: {}, {}", snapshot::dir(archive_dir, archive_ext), e);
+        return Status::Failed;
+    }
+    if let Err(e) = snapshot::set_layout(archive_dir, archive_ext, *LAYOUT) {
+        error!(log, "NOT able to record layout of snapshot {}, {}", archive_ext, e);
+        return Status::Failed;
+    }
+    // remove partial exports left behind by an earlier interrupted run
+    let mut partial_dirs = vec![snapshot::dir(archive_dir, archive_ext)];
+    if *LAYOUT == Layout::Objects && Path::new(&objects::dir(archive_dir)).is_dir() {
+        partial_dirs.push(objects::dir(archive_dir));
+    }
+    for dir in partial_dirs {
+        match backup::remove_temp_files(&dir) {
+            Ok(0) => (),
+            Ok(removed) => info!(log, "Removed {} partial exports", removed),
+            Err(e) => error!(log, "Unable to remove partial exports: {}", e),
+        }
+    }
+    // pick up where an earlier run of the snapshot that was shut down left off
+    let remaining_file = snapshot::remaining_file(archive_dir, archive_ext);
+    let mut resumed = match resume::load(&remaining_file) {
+        Ok(resumed) => resumed,
+        Err(e) => {
+            error!(log, "NOT able to read work left over: {}, {}", remaining_file, e);
+            return Status::Failed;
+        },
+    };
+    let mut summary = Summary::new(archive_ext);
+    // otherwise copies archived by an earlier run that did not finish are kept
+    // when intact, checked against the sizes it managed to record
+    let mut earlier = HashMap::new();
+    if resumed.is_none() {
+        match manifest::read_partial::<Entry>(&manifest_file) {
+            Ok(entries) => {
+                info!(log, "Resuming snapshot {} in place, {} nodes were recorded", archive_ext, entries.len());
+                earlier.extend(entries.into_iter().map(|entry| ((entry.repo, entry.path.clone()), entry)));
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => warn!(log, "Unable to read manifest: {}, {}", manifest_file, e),
+        }
+    }
+    let manifest = match resumed {
+        Some(ref mut resumed) => {
+            // nodes recorded by the earlier run count towards this one
+            match manifest::read::<Entry>(&manifest_file) {
+                Ok(entries) => for entry in &entries {
+                    summary.add(entry);
+                    resumed.listing.entry(entry);
+                },
+                Err(e) => {
+                    error!(log, "NOT able to read manifest: {}, {}", manifest_file, e);
+                    return Status::Failed;
+                },
+            }
+            info!(log, "Resuming snapshot {} with {} repos, {} sites, and {} paths left", archive_ext, resumed.repos.len(), resumed.sites.len(), resumed.paths.len());
+            manifest::Writer::append(&manifest_file)
+        },
+        None => manifest::Writer::create(&manifest_file),
+    };
+    let mut manifest = match manifest {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            error!(log, "NOT able to create manifest: {}, {}", manifest_file, e);
+            return Status::Failed;
+        },
+    };
+    // work left over is only resumed once, a run that is shut down again leaves its own
+    if resumed.is_some() {
+        if let Err(e) = fs::remove_file(&remaining_file) {
+            error!(log, "NOT able to remove work left over: {}, {}", remaining_file, e);
+            return Status::Failed;
+        }
+    }
+    let Remaining{ mut listing, mut errors, repos: left_repos, sites: left_sites, paths: left_paths } = resumed.unwrap_or_else(|| {
+        Remaining{ repos: repos.iter().map(|repo| repo.repo_type).collect(), ..Remaining::new() }
+    });
+    let (results_s, results_r) = channel::unbounded::<Entry>();
+    let recorder = thread::spawn(move || -> io::Result<(usize, Summary)> {
+        for entry in results_r {
+            manifest.write(&entry)?;
+            summary.add(&entry);
+        }
+        Ok((manifest.finish()?, summary))
+    });
+
+    let health = Arc::new(Health::new(backup_urls.len(), pool.breaker_failures, pool.breaker_cool_down));
+    let settings = Arc::new(worker::Settings{
+        archive_dir,
+        archive_ext,
+        layout: *LAYOUT,
+        compression: *COMPRESSION,
+        storage,
+        previous_checksums: previous_exts.iter().map(|previous_ext| {
+            let checksums = manifest::checksums(&snapshot::manifest_file(archive_dir, previous_ext)).unwrap_or_else(|e| {
+                warn!(log, "Unable to read checksums of previous snapshot {}, {}", previous_ext, e);
+                HashMap::new()
+            });
+            (previous_ext.clone(), checksums)
+        }).collect(),
+        previous_archived: previous_exts.iter().map(|previous_ext| {
+            let archived = manifest::archived(&snapshot::manifest_file(archive_dir, previous_ext)).unwrap_or_else(|e| {
+                warn!(log, "Unable to read manifest of previous snapshot {}, {}", previous_ext, e);
+                HashMap::new()
+            });
+            (previous_ext.clone(), archived)
+        }).collect(),
+        previous_exts: previous_exts.clone(),
+        policies: repos.iter().map(|repo| (repo.repo_type, repo.retry)).collect(),
+        earlier,
+        in_place,
+        remote: S3_URL.is_some(),
+        exports: Limit::new(pool.exports),
+        repo_exports: repos.iter().map(|repo| (repo.repo_type, Limit::new(repo.exports))).collect(),
+    });
+    let worker_count = pool.workers.iter().sum::<usize>();
+    let (s, r) = channel::bounded(worker_count.max(1));
+    let queue = Arc::new(Queue::new(r, worker_count));
+    let mut workers = Vec::new();
+    for (backend, url) in backup_urls.iter().enumerate() {
+        for _ in 0..pool.workers[backend] {
+            let worker = Worker::new(workers.len(), backend, url, settings.clone(), health.clone());
+            let thread_queue = queue.clone();
+            let results = results_s.clone();
+            workers.push(thread::spawn(move || worker.run(&thread_queue, &results)));
+        }
+    }
+
+    let aborted = AtomicBool::new(false);
+    let mut remaining = Remaining::new();
+    let mut metrics = Metrics::new();
+    thread::scope(|scope| {
+        // paths of each site are listed by the listers while the
+        // sites of the remaining repos are still being listed
+        let (sites_s, sites_r) = channel::unbounded();
+        let listers: Vec<_> = (0..pool.listers).map(|n| {
+            let lister = Lister::new(backup_urls, n % backup_urls.len(), logging::Context::new(format!("l{}", n)).run(archive_ext), health.clone());
+            let (sites_r, queue, s, results_s, aborted) = (sites_r.clone(), &*queue, &s, &results_s, &aborted);
+            scope.spawn(move || lister.run(sites_r, queue, s, results_s, aborted))
+        }).collect();
+        drop(sites_r);
+        // paths and sites left over by an earlier run go first
+        for path in left_paths {
+            listing.path(&path);
+            lister::dispatch(&log, &queue, &s, &results_s, path, &mut remaining);
+        }
+        for site in left_sites {
+            let repo = match repos.iter().find(|repo| repo.repo_type == site.repo_type) {
+                Some(repo) => repo,
+                None => continue,
+            };
+            if shutdown::is_requested() {
+                remaining.sites.push(site);
+            } else if let Err(e) = sites_s.send((repo, site)) {
+                error!(log.path(&(e.0).1), "No listers available for site {}", &(e.0).1.path);
+                break;
+            }
+        }
+        let mut lister = Lister::new(backup_urls, 0, log.clone(), health.clone());
+        for repo in repos.iter().filter(|repo| left_repos.contains(&repo.repo_type)) {
+            if aborted.load(Ordering::Relaxed) {
+                break;
+            }
+            let mut attempts = retry::Attempts::new(repo.retry);
+            let sites = match lister.list(&mut attempts, &repo.repo_type.to_string(), None, "sites", |magnolia| magnolia.sites(repo.repo_type)) {
+                Listed::Done(Some(sites)) => sites,
+                Listed::Done(None) | Listed::Failed => {
+                    error!(log, "Unable to retrieve sites for repo {}", repo.repo_type);
+                    errors.push(format!("Unable to retrieve sites for repo {}", repo.repo_type));
+                    continue;
+                },
+                Listed::Abort => {
+                    aborted.store(true, Ordering::Relaxed);
+                    break;
+                },
+                Listed::Interrupted => {
+                    remaining.repos.push(repo.repo_type);
+                    continue;
+                },
+            };
+            listing.repo(repo.repo_type);
+            for site in sites.into_iter().filter(|site| repo.has_site(backup::extract_site(&site.path))) {
+                if shutdown::is_requested() {
+                    remaining.sites.push(site);
+                    continue;
                 }
-                if let Ok(mut file) = File::create(&archive_file) {
-                    loop {
-                        match magnolia.export(&path) {
-                            Ok(mut export) => {
-                                match io::copy(&mut export, &mut file) {
-                                    Ok(size) => {
-                                        let timestamp = FileTime::from_unix_time(path.last_modified.unwrap().timestamp(), path.last_modified.unwrap().timestamp_subsec_nanos());
-                                        if let Err(e) = set_file_times(&archive_file, timestamp, timestamp) {
-                                            println!("ERROR[{}]: {}, {}", thread_n, &path.path, e);
-                                        } else {
-                                            println!("INFO[{}]: Exported {} bytes {}", thread_n, size, &path.path);
-                                        }
-                                    },
-                                    // TODO: Remove file if bad copy.
-                                    Err(e) => println!("ERROR[{}]: Export failed {}, {}", thread_n, &path.path, e),
-                                }
-                                break;
-                            },
-                            Err(FetchError::LostSession{error: e}) => {
-                                println!("WARN[{}]: {}, session: {:?}, {}", thread_n, &path.path, magnolia.session, e);
-                                if let Err(e) = magnolia.new_client() {
-                                    println!("ERROR[{}]: {}, {}", thread_n, &path.path, e);
-                                    return;
-                                }
-                            },
-                            Err(FetchError::BackOff{error: e}) => {
-                                println!("WARN[{}]: {}, session: {:?} {}", thread_n, &path.path, magnolia.session, e);
-                                thread::sleep(Duration::new(15, 0));
-                                // Reset connection and renew session as magnolia cannot
-                                // recover a persistent connection after a server error
-                                // Also it looks like this specific request if retried
-                                // will keep generating 500's so skipping after a pause
-                                // rather then only backing off and retrying.
-                                // TODO: at some point if we find other issues that are
-                                // recoverable with a retry then we may want to retry
-                                // one more time.
-                                if let Err(e) = magnolia.new_client() {
-                                    println!("ERROR[{}]: {}, {}", thread_n, &path.path, e);
-                                    return;
-                                }
-                                break;
-                            },
-                            Err(FetchError::Skip{error: e}) => {
-                                println!("ERROR[{}]: {}, {}", thread_n, &path.path, e);
-                                break;
-                            },
-                            Err(FetchError::Blocking{error: e}) => {
-                                println!("ERROR[{}]: {}, {}", thread_n, &path.path, e);
-                                return;
-                            },
-                        }
+                // objects are stored outside of the snapshot, as are exports stored in S3
+                if *LAYOUT == Layout::Files && S3_URL.is_none() {
+                    let archive_path = backup::archive_path(archive_dir, archive_ext, &site);
+                    if let Err(e) = DirBuilder::new().recursive(true).create(&archive_path) {
+                        error!(log.path(&site), "NOT able to create archive directory: {}, {}", archive_path, e);
+                        listing.failed_site(&site);
+                        errors.push(format!("Unable to list {} {}", repo.repo_type, &site.path));
+                        continue;
                     }
                 }
+                if let Err(e) = sites_s.send((repo, site)) {
+                    error!(log.path(&(e.0).1), "No listers available for site {}", &(e.0).1.path);
+                    break;
+                }
             }
-        });
+        }
+        drop(sites_s);
+        metrics.merge(lister.finish());
+        for lister in listers {
+            match lister.join() {
+                Ok((lister_listing, lister_errors, lister_metrics, lister_remaining)) => {
+                    listing.merge(lister_listing);
+                    errors.extend(lister_errors);
+                    metrics.merge(lister_metrics);
+                    remaining.merge(lister_remaining);
+                },
+                Err(_) => {
+                    error!(log, "Lister terminated unexpectedly");
+                    errors.push("Lister terminated unexpectedly".to_string());
+                },
+            }
+        }
+    });
+    drop(s);
+    let aborted = aborted.into_inner();
+    for worker in workers {
+        match worker.join() {
+            Ok(worker_metrics) => metrics.merge(worker_metrics),
+            Err(_) => {
+                error!(log, "Worker terminated unexpectedly");
+                errors.push("Worker terminated unexpectedly".to_string());
+            },
+        }
+    }
+    // paths not done are left for the next run when shut down,
+    // otherwise the workers that were to export them are gone
+    for path in queue.drain() {
+        if shutdown::is_requested() {
+            remaining.paths.push(path);
+        } else if let Err(e) = results_s.send(Entry::failed(&path, "No workers available")) {
+            error!(log.path(&path), "{}, {}", &path.path, e);
+        }
+    }
+    drop(results_s);
+    let interrupted = !aborted && !remaining.is_empty();
+    if interrupted {
+        info!(log, "Shut down with {} repos, {} sites, and {} paths left", remaining.repos.len(), remaining.sites.len(), remaining.paths.len());
+        remaining.listing = listing;
+        remaining.errors = errors.clone();
+        if let Err(e) = remaining.save(&remaining_file) {
+            error!(log, "NOT able to save work left over: {}, {}", remaining_file, e);
+        }
+    } else if !aborted {
+        if let Some(previous_ext) = previous_exts.first() {
+            record_deletions(&log, repos, &listing, archive_dir, archive_ext, previous_ext);
+        }
+    }
+    let mut summary = match recorder.join() {
+        Ok(Ok((count, summary))) => {
+            info!(log, "Recorded {} nodes in manifest", count);
+            summary
+        },
+        Ok(Err(e)) => {
+            error!(log, "NOT able to write manifest, {}", e);
+            return Status::Failed;
+        },
+        Err(_) => {
+            error!(log, "Manifest writer terminated unexpectedly");
+            return Status::Failed;
+        },
+    };
+    for error in errors {
+        summary.error(error);
+    }
+    summary.aborted = aborted;
+    summary.interrupted = interrupted;
+    let status = summary.finish(start.elapsed());
+    if let Err(e) = summary.save(&snapshot::summary_file(archive_dir, archive_ext)) {
+        error!(log, "NOT able to write summary, {}", e);
+    }
+    if let Some(metrics_file) = metrics_file {
+        if let Err(e) = metrics.save(metrics_file, &summary) {
+            error!(log, "NOT able to write metrics: {}, {}", metrics_file, e);
+        }
+    }
+    if logging::is_json() {
+        println!("{}", serde_json::to_string(&summary).unwrap());
+    } else {
+        print!("{}", summary.report());
+    }
+    if aborted {
+        error!(log, "Snapshot {} was NOT completed", archive_ext);
+    } else if interrupted {
+        warn!(log, "Snapshot {} was NOT completed, it is resumed by the next run of the snapshot", archive_ext);
+    } else if let Err(e) = snapshot::complete(archive_dir, archive_ext) {
+        error!(log, "NOT able to mark snapshot {} complete, {}", archive_ext, e);
+        return Status::Failed;
     }
+    status
+}
 
-    let mut magnolia = Fetch::new(&primary_url).unwrap();
-    if let Ok(Some(sites)) = magnolia.sites(repos::RepoType::Dam) {
-        for site in sites {
-            let archive_path = backup::archive_path(archive_dir, archive_ext, &site);
-            match DirBuilder::new().recursive(true).create(&archive_path) {
+fn prune(archive_dir: &str, policy: &prune::Policy, dry_run: bool) -> Status {
+    let log = logging::Context::new("m");
+    let exts = match prune::plan(archive_dir, policy) {
+        Ok(exts) => exts,
+        Err(e) => {
+            error!(log, "Unable to list snapshots in {}, {}", archive_dir, e);
+            return Status::Failed;
+        },
+    };
+    let storage = storage_or_usage(archive_dir);
+    let mut status = Status::Success;
+    let mut reclaimed = 0;
+    for ext in exts.iter() {
+        if dry_run {
+            info!(log, "Would prune snapshot {}", ext);
+            continue;
+        }
+        // exports in S3 are removed while the manifest listing their sites is still around
+        if S3_URL.is_some() {
+            match storage::remove_snapshot(&*storage, &snapshot::manifest_file(archive_dir, ext), ext) {
+                Ok(removed) => info!(log, "Removed {} exports of snapshot {} from S3", removed, ext),
+                Err(e) => {
+                    error!(log, "NOT able to remove the exports of snapshot {} from S3, {}", ext, e);
+                    status = Status::Partial;
+                    continue;
+                },
+            }
+        }
+        match prune::remove(Path::new(&snapshot::dir(archive_dir, ext))) {
+            Ok(bytes) => {
+                info!(log, "Pruned snapshot {} reclaiming {} bytes", ext, bytes);
+                reclaimed += bytes;
+            },
+            Err(e) => {
+                error!(log, "NOT able to prune snapshot {}, {}", ext, e);
+                status = Status::Partial;
+            },
+        }
+    }
+    // objects no longer used by any snapshot left
+    let collected = prune::referenced(archive_dir, if dry_run { &exts } else { &[] })
+        .and_then(|referenced| objects::collect(archive_dir, &referenced, dry_run));
+    match collected {
+        Ok((0, _)) => (),
+        Ok((removed, bytes)) if dry_run => info!(log, "Would remove {} unused objects of {} bytes", removed, bytes),
+        Ok((removed, bytes)) => {
+            info!(log, "Removed {} unused objects reclaiming {} bytes", removed, bytes);
+            reclaimed += bytes;
+        },
+        Err(e) => {
+            error!(log, "NOT able to remove unused objects, {}", e);
+            status = Status::Partial;
+        },
+    }
+    if !dry_run {
+        info!(log, "Reclaimed {} bytes", reclaimed);
+    }
+    status
+}
+
+fn restore(archive_dir: &str, archive_ext: &str, repo_type: repos::RepoType, path: &str, uuid_behavior: UuidBehavior, dry_run: bool) -> Status {
+    let log = logging::Context::new("m");
+    if !snapshot::is_complete(archive_dir, archive_ext) {
+        warn!(log, "Snapshot {} was NOT completed", archive_ext);
+    }
+    let storage = storage_or_usage(archive_dir);
+    let restores = match restore::plan(&*storage, archive_dir, archive_ext, repo_type, path) {
+        Ok(restores) => restores,
+        Err(e) => {
+            error!(log, "Unable to find {} {} in snapshot {}, {}", repo_type, path, archive_ext, e);
+            return Status::Failed;
+        },
+    };
+    if restores.is_empty() {
+        error!(log, "Nothing to restore for {} {} in snapshot {}", repo_type, path, archive_ext);
+        return Status::Failed;
+    }
+    if dry_run {
+        for restore in restores {
+            info!(log, "Would import {} {} from {}", repo_type, restore.path.path, restore.file);
+        }
+        return Status::Success;
+    }
+    // import through the first of BACKUP_URLS that accepts a connection
+    let mut magnolia = match BACKUP_URLS.iter().find_map(|url| Fetch::new(url).map_err(|e| {
+        warn!(log.clone().backend(url), "Unable to connect, {}", e);
+    }).ok()) {
+        Some(magnolia) => magnolia,
+        None => {
+            error!(log, "Unable to connect to any of {} backup URLs", BACKUP_URLS.len());
+            return Status::Failed;
+        },
+    };
+    let mut imported = 0;
+    let mut failed = 0;
+    for restore in restores {
+        loop {
+            let data = match restore.open(&*storage) {
+                Ok(data) => data,
+                Err(e) => {
+                    error!(log, "{}, {}", restore.file, e);
+                    failed += 1;
+                    break;
+                },
+            };
+            match magnolia.import(&restore.path, data, uuid_behavior) {
                 Ok(()) => {
-                    loop {
-                        match magnolia.paths(&site) {
-                            Ok(Some(paths)) => {
-                                for path in paths {
-                                    if let Err(error) = s.send(path.clone()) {
-                                        println!("ERROR[m]: dam: {} path: {}; {:?}", path.repo_type, path.path, error);
-                                    }
-                                }
-                                break;
-                            },
-                            Ok(None) => {
-                                println!("INFO[m]: No paths for site {}", &site.path);
-                                break;
-                            },
-                            Err(FetchError::LostSession{error: e}) => {
-                                println!("WARN[m]: {}, session: {:?}, {}", &site.path, magnolia.session, e);
-                                if let Err(e) = magnolia.new_client() {
-                                    println!("ERROR[m]: {}, {}", &site.path, e);
-                                    return;
-                                }
-                            },
-                            Err(FetchError::BackOff{error: e}) => {
-                                // TODO: Exponential backoff currently waits 15 seconds.
-                                // NOTE: Retry behavior basically removed as
-                                // some requests will always generate 500's,
-                                // basically turning this into a skip after delay.
-                                println!("WARN[m]: {}, session: {:?}, {}", &site.path, magnolia.session, e);
-                                thread::sleep(Duration::new(15, 0));
-                                // Reset connection and renew session as magnolia cannot
-                                // recover a persistent connection after a server error
-                                if let Err(e) = magnolia.new_client() {
-                                    println!("ERROR[m]: {}, {}", &site.path, e);
-                                    return;
-                                }
-                                break;
-                            },
-                            Err(FetchError::Skip{error: e}) => {
-                                println!("ERROR[m]: {}, {}", &site.path, e);
-                                break;
-                            },
-                            Err(FetchError::Blocking{error: e}) => {
-                                println!("ERROR[m]: {}, {}", &site.path, e);
-                                return;
-                            },
-                        }
+                    info!(log.path(&restore.path), "Imported {} {}", repo_type, restore.path.path);
+                    imported += 1;
+                    break;
+                },
+                Err(error @ FetchError::LostSession{..}) => {
+                    warn!(log.path(&restore.path).fetch_error(&error).session(&magnolia.session), "{}, session: {:?}, {}", &restore.path.path, magnolia.session, error);
+                    if let Err(e) = magnolia.new_client() {
+                        error!(log.path(&restore.path), "{}, {}", &restore.path.path, e);
+                        return if imported > 0 { Status::Partial } else { Status::Failed };
                     }
                 },
-                Err(e) => println!("ERROR[m]: NOT able to create archive directory: {}, {}", archive_path, e),
+                Err(error @ FetchError::Blocking{..}) => {
+                    error!(log.path(&restore.path).fetch_error(&error), "{}, {}", &restore.path.path, error);
+                    return if imported > 0 { Status::Partial } else { Status::Failed };
+                },
+                Err(error) => {
+                    error!(log.path(&restore.path).fetch_error(&error), "{}, {}", &restore.path.path, error);
+                    failed += 1;
+                    break;
+                },
             }
         }
-    } else {
-        println!("ERROR[m]: Unable to retrieve sites for repo {}", repos::RepoType::Dam);
     }
-    drop(s);
-    // wait up to 5 minutes to end main running thread
-    let mut force = true;
-    for _ in 0..10 {
-        if r.is_empty() {
-            force = false;
-            break;
+    match (imported, failed) {
+        (_, 0) => Status::Success,
+        (0, _) => Status::Failed,
+        _ => Status::Partial,
+    }
+}
+
+fn snapshot_diff(archive_dir: &str, from_ext: &str, to_ext: &str, json: bool) -> Status {
+    let log = logging::Context::new("m");
+    // exports stored in S3 are compared by what the manifests recorded of them
+    let nodes = |ext: &str| if S3_URL.is_some() { diff::stored(&snapshot::manifest_file(archive_dir, ext)) } else { diff::nodes(archive_dir, ext) }.map_err(|e| {
+        error!(log, "Unable to read snapshot {}, {}", ext, e);
+    });
+    let diffs = match (nodes(from_ext), nodes(to_ext)) {
+        (Ok(from), Ok(to)) => diff::diff(&from, &to),
+        _ => return Status::Failed,
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&diffs).unwrap());
+        return Status::Success;
+    }
+    for site_diff in diffs {
+        println!("{} {}", site_diff.repo, site_diff.site);
+        for (change, paths) in &[("+", &site_diff.added), ("-", &site_diff.removed), ("~", &site_diff.modified)] {
+            for path in paths.iter() {
+                println!("  {} {}", change, path);
+            }
+        }
+    }
+    Status::Success
+}
+
+// Run a command that modifies ARCHIVE_DIR while holding its lock so
+// a manual run cannot race the scheduled one.
+fn locked<F: FnOnce() -> Status>(command: F) -> Status {
+    let log = logging::Context::new("m");
+    let lock = match lock::acquire(&ARCHIVE_DIR) {
+        Ok(lock) => lock,
+        Err(e @ lock::LockError::Held{..}) => {
+            error!(log, "{}", e);
+            process::exit(EXIT_LOCKED);
+        },
+        Err(e) => {
+            error!(log, "{}", e);
+            return Status::Failed;
+        },
+    };
+    if let Some(ref stale) = lock.stale {
+        warn!(log, "Taking over stale lock of {}", stale);
+    }
+    let status = command();
+    drop(lock);
+    status
+}
+
+fn verify(archive_dir: &str, archive_ext: &str) -> Status {
+    let log = logging::Context::new("m");
+    if !snapshot::is_complete(archive_dir, archive_ext) {
+        warn!(log, "Snapshot {} was NOT completed", archive_ext);
+    }
+    let entries = match manifest::read::<Entry>(&snapshot::manifest_file(archive_dir, archive_ext)) {
+        Ok(entries) => entries,
+        Err(e) => {
+            error!(log, "Unable to read manifest of snapshot {}, {}", archive_ext, e);
+            return Status::Failed;
+        },
+    };
+    let layout = match snapshot::layout(archive_dir, archive_ext) {
+        Ok(layout) => layout,
+        Err(e) => {
+            error!(log, "Unable to read layout of snapshot {}, {}", archive_ext, e);
+            return Status::Failed;
+        },
+    };
+    let storage = storage_or_usage(archive_dir);
+    let (mut intact, mut unchecked, mut corrupted, mut missing) = (0, 0, 0, 0);
+    for entry in entries.iter().filter(|entry| entry.action.is_archived()) {
+        let (file, export) = match layout {
+            Layout::Files => {
+                let key = verify::archive_key(&*storage, archive_ext, entry);
+                let export = storage::open(&*storage, &key);
+                (key, export)
+            },
+            Layout::Objects => match verify::archive_file(archive_dir, archive_ext, layout, entry) {
+                Some(file) => {
+                    let export = compress::open(&file);
+                    (file, export)
+                },
+                None => {
+                    error!(log.path(&entry.path_info()), "Missing {} {}, no checksum to find its object by", entry.repo, entry.path);
+                    missing += 1;
+                    continue;
+                },
+            },
+        };
+        match verify::check(export, entry) {
+            verify::Check::Intact => intact += 1,
+            verify::Check::Unchecked => unchecked += 1,
+            verify::Check::Missing => {
+                error!(log.path(&entry.path_info()), "Missing {} {}, {}", entry.repo, entry.path, file);
+                missing += 1;
+            },
+            verify::Check::Corrupted(reason) => {
+                error!(log.path(&entry.path_info()), "Corrupted {} {}, {}", entry.repo, entry.path, reason);
+                corrupted += 1;
+            },
         }
-        thread::sleep(Duration::new(30, 0))
     }
-    if force {
-        println!("ERROR[m]: Forced to terminate main thread with outstanding requests");
+    info!(log, "Verified snapshot {}: {} intact, {} without checksums, {} corrupted, {} missing", archive_ext, intact, unchecked, corrupted, missing);
+    match (intact + unchecked, corrupted + missing) {
+        (_, 0) => Status::Success,
+        (0, _) => Status::Failed,
+        _ => Status::Partial,
+    }
+}
+
+fn pack(archive_dir: &str, archive_ext: &str, selection: &pack::Selection, file: &str) -> Status {
+    let log = logging::Context::new("m");
+    if S3_URL.is_some() {
+        error!(log, "NOT able to pack exports stored in S3");
+        return Status::Failed;
+    }
+    match pack::pack(archive_dir, archive_ext, selection, file) {
+        Ok(packed) => {
+            info!(log, "Packed {} exports of snapshot {} into {}", packed, archive_ext, file);
+            Status::Success
+        },
+        Err(e) => {
+            error!(log, "Unable to pack snapshot {} into {}, {}", archive_ext, file, e);
+            Status::Failed
+        },
+    }
+}
+
+fn unpack(archive_dir: &str, file: &str) -> Status {
+    let log = logging::Context::new("m");
+    match pack::unpack(archive_dir, file) {
+        Ok((archive_ext, unpacked)) => {
+            info!(log, "Unpacked {} exports of snapshot {} from {}", unpacked, archive_ext, file);
+            Status::Success
+        },
+        Err(e) => {
+            error!(log, "Unable to unpack {}, {}", file, e);
+            Status::Failed
+        },
+    }
+}
+
+// Read the settings of a backup before taking the lock, so invalid ones
+// exit with usage rather than leave the lock looking stale.
+fn configure_backup() {
+    lazy_static::initialize(&BACKUP_URLS);
+    lazy_static::initialize(&REPOS);
+    lazy_static::initialize(&ARCHIVE_EXT);
+    lazy_static::initialize(&PREVIOUS_COUNT);
+    lazy_static::initialize(&BREAKER_FAILURES);
+    lazy_static::initialize(&BREAKER_COOL_DOWN);
+    lazy_static::initialize(&WORKERS);
+    lazy_static::initialize(&MAX_EXPORTS);
+    lazy_static::initialize(&COMPRESSION);
+    lazy_static::initialize(&LAYOUT);
+    lazy_static::initialize(&LINK);
+    lazy_static::initialize(&LISTERS);
+}
+
+fn backup() -> Status {
+    let log = logging::Context::new("m");
+    let previous_exts = match *PREVIOUS_EXT {
+        Some(ref previous_ext) => vec![previous_ext.clone()],
+        None => snapshot::previous(&ARCHIVE_DIR, &ARCHIVE_EXT, *PREVIOUS_COUNT).unwrap_or_else(|e| {
+            error!(log, "Unable to find previous snapshots in {}, {}", *ARCHIVE_DIR, e);
+            Vec::new()
+        }),
+    };
+    info!(log, "Previous snapshots {:?}", previous_exts);
+    if let Err(e) = shutdown::register() {
+        warn!(log, "Unable to handle signals, {}", e);
     }
+    let pool = Pool{
+        workers: WORKERS.clone(),
+        exports: *MAX_EXPORTS,
+        listers: *LISTERS,
+        breaker_failures: *BREAKER_FAILURES,
+        breaker_cool_down: *BREAKER_COOL_DOWN,
+    };
+    run(&BACKUP_URLS, &REPOS, &ARCHIVE_DIR, &ARCHIVE_EXT, previous_exts, METRICS_FILE.as_deref(), &pool)
 }
 
 fn main() {
-    run(&*BACKUP_URLS, &ARCHIVE_DIR, &ARCHIVE_EXT, &PREVIOUS_EXT);
-    println!("Done");
+    logging::set_json(*LOG_JSON);
+    let args: Vec<String> = env::args().skip(1).collect();
+    let flag = |name: &str| args.iter().any(|arg| arg == name);
+    let option = |name: &str| args.iter().find_map(|arg| arg.strip_prefix(name).and_then(|arg| arg.strip_prefix('=')));
+    let positional: Vec<&str> = args.iter().map(|arg| arg.as_str()).filter(|arg| !arg.starts_with("--")).collect();
+    let status = match positional.as_slice() {
+        [] | ["backup"] => {
+            configure_backup();
+            locked(backup)
+        },
+        ["prune"] => {
+            let retention = &*RETENTION;
+            locked(|| prune(&ARCHIVE_DIR, retention, flag("--dry-run")))
+        },
+        ["restore", archive_ext, repo, path] => {
+            let repo_type = repo.parse().unwrap_or_else(|e| usage(&format!("Invalid repo {}, {}", repo, e)));
+            let uuid = option("--uuid").unwrap_or("throw");
+            let uuid_behavior = uuid.parse().unwrap_or_else(|e| usage(&format!("Invalid --uuid {}, {}", uuid, e)));
+            restore(&ARCHIVE_DIR, archive_ext, repo_type, path, uuid_behavior, flag("--dry-run"))
+        },
+        ["verify", archive_ext] => verify(&ARCHIVE_DIR, archive_ext),
+        ["pack", archive_ext, file, selection @ ..] if selection.len() <= 2 => {
+            let selection = pack::Selection{
+                repo: selection.first().map(|repo| repo.parse().unwrap_or_else(|e| usage(&format!("Invalid repo {}, {}", repo, e)))),
+                site: selection.get(1).map(|site| site.to_string()),
+            };
+            pack(&ARCHIVE_DIR, archive_ext, &selection, file)
+        },
+        ["unpack", file] => locked(|| unpack(&ARCHIVE_DIR, file)),
+        // output of diff is the report itself
+        ["diff", from_ext, to_ext] => process::exit(snapshot_diff(&ARCHIVE_DIR, from_ext, to_ext, flag("--json")).exit_code()),
+        _ => {
+            println!("{}", USAGE);
+            process::exit(EXIT_USAGE);
+        },
+    };
+    info!(logging::Context::new("m"), "Done");
+    process::exit(status.exit_code());
 }
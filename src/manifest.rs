@@ -0,0 +1,222 @@
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::fs::{File, OpenOptions};
+use std::fmt::Display;
+use std::collections::HashMap;
+use serde_json;
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use failure::Error;
+use chrono::{DateTime, Local};
+use repos::RepoType;
+use nodes::PathInfo;
+use backup;
+
+/// How a node made it into the snapshot, if at all. Skipped nodes had their
+/// export request skipped after a server or request error, while kept nodes
+/// were already archived by an earlier run of the snapshot that did not finish.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Exported,
+    Linked,
+    Kept,
+    Skipped,
+    Failed,
+}
+
+impl Action {
+    /// Determine if a copy of the node is held in the snapshot
+    pub fn is_archived(self) -> bool {
+        self == Action::Exported || self == Action::Linked || self == Action::Kept
+    }
+}
+
+/// Manifest entry recording the outcome of backing up a single node
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub repo: RepoType,
+    pub site: String,
+    pub path: String,
+    pub last_modified: Option<DateTime<Local>>,
+    pub size: Option<u64>,
+    /// Hex encoded SHA-256 of the archived export, missing from older manifests
+    #[serde(default)]
+    pub sha256: Option<String>,
+    pub action: Action,
+    pub error: Option<String>,
+}
+
+/// What a manifest recorded of the export of an archived node. Its size and
+/// SHA-256 are of the export itself whether or not it was compressed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Checksum {
+    pub last_modified: Option<DateTime<Local>>,
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl Checksum {
+    /// Checksum recorded by entry, if it recorded one
+    pub fn of(entry: &Entry) -> Option<Checksum> {
+        Some(Checksum{ last_modified: entry.last_modified, size: entry.size?, sha256: entry.sha256.clone()? })
+    }
+}
+
+/// Checksums of the archived nodes of a snapshot by repo and path
+pub type Checksums = HashMap<(RepoType, String), Checksum>;
+
+/// Last modified times of the nodes archived in a snapshot
+pub type Archived = HashMap<(RepoType, String), Option<DateTime<Local>>>;
+
+impl Entry {
+    pub fn new(path: &PathInfo, action: Action, size: Option<u64>) -> Entry {
+        Entry{
+            repo: path.repo_type,
+            site: backup::extract_site(&path.path).to_string(),
+            path: path.path.clone(),
+            last_modified: path.last_modified,
+            size,
+            sha256: None,
+            action,
+            error: None,
+        }
+    }
+
+    /// Node the entry was recorded for
+    pub fn path_info(&self) -> PathInfo {
+        PathInfo{ repo_type: self.repo, path: self.path.clone(), last_modified: self.last_modified }
+    }
+
+    pub fn failed<D: Display>(path: &PathInfo, error: D) -> Entry {
+        Entry::new(path, Action::Failed, None).with_error(error)
+    }
+
+    pub fn skipped<D: Display>(path: &PathInfo, error: D) -> Entry {
+        Entry::new(path, Action::Skipped, None).with_error(error)
+    }
+
+    pub fn with_error<D: Display>(mut self, error: D) -> Entry {
+        self.error = Some(error.to_string());
+        self
+    }
+
+    pub fn with_sha256(mut self, sha256: String) -> Entry {
+        self.sha256 = Some(sha256);
+        self
+    }
+}
+
+/// Writes manifest entries, or any other records, as JSON lines
+pub struct Writer {
+    file: BufWriter<File>,
+    count: usize,
+}
+
+impl Writer {
+    pub fn create(file: &str) -> io::Result<Writer> {
+        Ok(Writer{ file: BufWriter::new(File::create(file)?), count: 0 })
+    }
+
+    /// Add to the entries of an earlier run, counting only those written now
+    pub fn append(file: &str) -> io::Result<Writer> {
+        Ok(Writer{ file: BufWriter::new(OpenOptions::new().create(true).append(true).open(file)?), count: 0 })
+    }
+
+    pub fn write<T: Serialize>(&mut self, entry: &T) -> io::Result<()> {
+        serde_json::to_writer(&mut self.file, entry)?;
+        self.file.write_all(b"\n")?;
+        self.count += 1;
+        Ok(())
+    }
+
+    /// Flush and sync manifest to disk returning the number of entries written.
+    pub fn finish(self) -> io::Result<usize> {
+        let file = self.file.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()?;
+        Ok(self.count)
+    }
+}
+
+/// Read the entries of a manifest left behind by a run that did not finish,
+/// leaving out any line it was cut short in the middle of.
+pub fn read_partial<T: DeserializeOwned>(file: &str) -> io::Result<Vec<T>> {
+    let mut entries = Vec::new();
+    for line in BufReader::new(File::open(file)?).lines() {
+        if let Ok(entry) = serde_json::from_str(&line?) {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Read the checksums of the nodes archived in a snapshot from its manifest
+pub fn checksums(file: &str) -> Result<Checksums, Error> {
+    Ok(read::<Entry>(file)?.into_iter()
+        .filter(|entry| entry.action.is_archived())
+        .filter_map(|entry| Some(((entry.repo, entry.path.clone()), Checksum::of(&entry)?)))
+        .collect())
+}
+
+/// Read the last modified times of the nodes archived in a snapshot from its
+/// manifest, including those it recorded no checksum for
+pub fn archived(file: &str) -> Result<Archived, Error> {
+    Ok(read::<Entry>(file)?.into_iter()
+        .filter(|entry| entry.action.is_archived())
+        .map(|entry| ((entry.repo, entry.path), entry.last_modified))
+        .collect())
+}
+
+/// Read all entries of a manifest, or any other JSON lines file
+pub fn read<T: DeserializeOwned>(file: &str) -> Result<Vec<T>, Error> {
+    let mut entries = Vec::new();
+    for line in BufReader::new(File::open(file)?).lines() {
+        let line = line?;
+        if !line.is_empty() {
+            entries.push(serde_json::from_str(&line)?);
+        }
+    }
+    Ok(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use backup::test_dir;
+
+    #[test]
+    fn test_write_and_read_manifest() {
+        let dir = test_dir("manifest");
+        let file = format!("{}/manifest.jsonl", dir);
+        let path = PathInfo{
+            repo_type: RepoType::Dam,
+            path: "/gato/subpage/basilisk.gif".to_string(),
+            last_modified: Some("2016-06-30T12:17:18.324-05:00".parse::<DateTime<Local>>().unwrap()),
+        };
+        let entries = vec![
+            Entry::new(&path, Action::Exported, Some(42)).with_sha256("ab".repeat(32)),
+            Entry::failed(&path, "Skip error type: timed out"),
+        ];
+        let mut manifest = Writer::create(&file).unwrap();
+        for entry in &entries {
+            manifest.write(entry).unwrap();
+        }
+        assert_eq!(manifest.finish().unwrap(), 2);
+        assert!(fs::read_to_string(&file).unwrap().starts_with(r#"{"repo":"dam","site":"gato","path":"/gato/subpage/basilisk.gif","#));
+        assert_eq!(read::<Entry>(&file).unwrap(), entries);
+        let checksums = checksums(&file).unwrap();
+        assert_eq!(checksums.len(), 1);
+        assert_eq!(checksums[&(RepoType::Dam, "/gato/subpage/basilisk.gif".to_string())], Checksum{ last_modified: path.last_modified, size: 42, sha256: "ab".repeat(32) });
+        // older manifests have no checksums
+        let older = format!("{}/older.jsonl", dir);
+        fs::write(&older, r#"{"repo":"dam","site":"gato","path":"/gato/a.gif","last_modified":null,"size":1,"action":"linked","error":null}"#).unwrap();
+        assert_eq!(read::<Entry>(&older).unwrap()[0].sha256, None);
+        assert!(super::checksums(&older).unwrap().is_empty());
+        assert_eq!(archived(&older).unwrap()[&(RepoType::Dam, "/gato/a.gif".to_string())], None);
+        assert_eq!(archived(&file).unwrap().len(), 1);
+        fs::write(&file, fs::read_to_string(&file).unwrap() + r#"{"repo":"dam","site":"ga"#).unwrap();
+        assert!(read::<Entry>(&file).is_err());
+        assert_eq!(read_partial::<Entry>(&file).unwrap(), entries);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,226 @@
+use std::io;
+use std::fs;
+use std::fmt::Write;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use chrono::Local;
+use fetch::FetchError;
+use summary::{Counts, Status, Summary};
+use backup;
+
+/// Upper bounds in seconds of the request latency histogram buckets
+const BUCKETS: [f64; 10] = [0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+
+const LAST_SUCCESS: &str = "pagers_last_success_timestamp_seconds";
+
+/// Latency histogram with cumulative counts per bucket of BUCKETS
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    pub fn observe(&mut self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        for (bucket, le) in self.buckets.iter_mut().zip(BUCKETS.iter()) {
+            if secs <= *le {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += secs;
+    }
+
+    fn merge(&mut self, other: &Histogram) {
+        for (bucket, count) in self.buckets.iter_mut().zip(other.buckets.iter()) {
+            *bucket += count;
+        }
+        self.count += other.count;
+        self.sum += other.sum;
+    }
+}
+
+/// Request metrics gathered by each thread, keyed by backend host:port
+/// so those from threads working against the same backend can be merged.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    fetch_errors: BTreeMap<(String, &'static str), u64>,
+    session_renewals: BTreeMap<String, u64>,
+    requests: BTreeMap<(String, &'static str), Histogram>,
+}
+
+impl Metrics {
+    pub fn new() -> Metrics {
+        Metrics::default()
+    }
+
+    pub fn fetch_error(&mut self, backend: &str, error: &FetchError) {
+        *self.fetch_errors.entry((backend.to_string(), error.kind())).or_insert(0) += 1;
+    }
+
+    pub fn session_renewal(&mut self, backend: &str) {
+        *self.session_renewals.entry(backend.to_string()).or_insert(0) += 1;
+    }
+
+    /// Record the latency of a request such as sites, paths, or export
+    pub fn request(&mut self, backend: &str, request: &'static str, duration: Duration) {
+        self.requests.entry((backend.to_string(), request)).or_default().observe(duration);
+    }
+
+    pub fn merge(&mut self, other: Metrics) {
+        for (key, count) in other.fetch_errors {
+            *self.fetch_errors.entry(key).or_insert(0) += count;
+        }
+        for (backend, count) in other.session_renewals {
+            *self.session_renewals.entry(backend).or_insert(0) += count;
+        }
+        for (key, histogram) in other.requests {
+            self.requests.entry(key).or_default().merge(&histogram);
+        }
+    }
+
+    /// Render the metrics of a finished run in the Prometheus text format.
+    /// last_success is the time of the most recent successful run, if any.
+    pub fn render(&self, summary: &Summary, last_success: Option<i64>) -> String {
+        let mut text = String::new();
+        let header = |text: &mut String, name: &str, kind: &str, help: &str| {
+            writeln!(text, "# HELP {} {}", name, help).unwrap();
+            writeln!(text, "# TYPE {} {}", name, kind).unwrap();
+        };
+        header(&mut text, "pagers_last_run_timestamp_seconds", "gauge", "Time the last backup run finished.");
+        writeln!(text, "pagers_last_run_timestamp_seconds {}", summary.finished.unwrap_or_else(Local::now).timestamp()).unwrap();
+        if let Some(last_success) = last_success {
+            header(&mut text, LAST_SUCCESS, "gauge", "Time the last successful backup run finished.");
+            writeln!(text, "{} {}", LAST_SUCCESS, last_success).unwrap();
+        }
+        header(&mut text, "pagers_last_run_status", "gauge", "Exit code of the last backup run, 0 success, 1 partial, 2 failed, 3 interrupted.");
+        writeln!(text, "pagers_last_run_status {}", summary.status.exit_code()).unwrap();
+        header(&mut text, "pagers_last_run_duration_seconds", "gauge", "Duration of the last backup run.");
+        writeln!(text, "pagers_last_run_duration_seconds {}", summary.duration_secs).unwrap();
+
+        let mut repos: BTreeMap<&str, Counts> = BTreeMap::new();
+        for (repo, sites) in &summary.repos {
+            let counts = repos.entry(repo).or_default();
+            for site in sites.values() {
+                counts.merge(site);
+            }
+        }
+        header(&mut text, "pagers_nodes", "gauge", "Nodes of the last backup run by repo and action.");
+        for (repo, counts) in &repos {
+            for (action, count) in &[("exported", counts.exported), ("linked", counts.linked), ("kept", counts.kept), ("skipped", counts.skipped), ("failed", counts.failed)] {
+                writeln!(text, "pagers_nodes{{repo=\"{}\",action=\"{}\"}} {}", repo, action, count).unwrap();
+            }
+        }
+        header(&mut text, "pagers_written_bytes", "gauge", "Bytes exported by the last backup run by repo.");
+        for (repo, counts) in &repos {
+            writeln!(text, "pagers_written_bytes{{repo=\"{}\"}} {}", repo, counts.bytes).unwrap();
+        }
+
+        let backends: Vec<&String> = {
+            let mut backends: Vec<&String> = self.requests.keys().map(|(backend, _)| backend)
+                .chain(self.fetch_errors.keys().map(|(backend, _)| backend))
+                .chain(self.session_renewals.keys())
+                .collect();
+            backends.sort();
+            backends.dedup();
+            backends
+        };
+        header(&mut text, "pagers_fetch_errors", "gauge", "Failed requests of the last backup run by backend and error.");
+        for backend in &backends {
+            for kind in FetchError::KINDS.iter() {
+                let count = self.fetch_errors.get(&(backend.to_string(), *kind)).unwrap_or(&0);
+                writeln!(text, "pagers_fetch_errors{{backend=\"{}\",error=\"{}\"}} {}", backend, kind, count).unwrap();
+            }
+        }
+        header(&mut text, "pagers_session_renewals", "gauge", "Sessions renewed during the last backup run by backend.");
+        for backend in &backends {
+            writeln!(text, "pagers_session_renewals{{backend=\"{}\"}} {}", backend, self.session_renewals.get(*backend).unwrap_or(&0)).unwrap();
+        }
+        header(&mut text, "pagers_request_duration_seconds", "histogram", "Latency of requests during the last backup run by backend and request.");
+        for ((backend, request), histogram) in &self.requests {
+            let labels = format!("backend=\"{}\",request=\"{}\"", backend, request);
+            for (le, count) in BUCKETS.iter().zip(histogram.buckets.iter()) {
+                writeln!(text, "pagers_request_duration_seconds_bucket{{{},le=\"{}\"}} {}", labels, le, count).unwrap();
+            }
+            writeln!(text, "pagers_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}", labels, histogram.count).unwrap();
+            writeln!(text, "pagers_request_duration_seconds_sum{{{}}} {}", labels, histogram.sum).unwrap();
+            writeln!(text, "pagers_request_duration_seconds_count{{{}}} {}", labels, histogram.count).unwrap();
+        }
+        text
+    }
+
+    /// Write the metrics of a finished run to a textfile for the node_exporter
+    /// textfile collector. The last success time is carried over from the
+    /// previous textfile when this run did not succeed.
+    pub fn save(&self, file: &str, summary: &Summary) -> io::Result<u64> {
+        let last_success = if summary.status == Status::Success {
+            summary.finished.map(|finished| finished.timestamp())
+        } else {
+            fs::read_to_string(file).ok().and_then(|text| last_success(&text))
+        };
+        let text = self.render(summary, last_success);
+        backup::write_file(file, &mut text.as_bytes(), None)
+    }
+}
+
+/// Time of the last successful run recorded in a textfile
+fn last_success(text: &str) -> Option<i64> {
+    text.lines()
+        .filter_map(|line| line.strip_prefix(LAST_SUCCESS))
+        .find_map(|value| value.trim().parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nodes::PathInfo;
+    use repos::RepoType;
+    use manifest::{Action, Entry};
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = Histogram::default();
+        histogram.observe(Duration::from_millis(70));
+        histogram.observe(Duration::from_secs(2));
+        histogram.observe(Duration::from_secs(120));
+        assert_eq!(histogram.buckets, [0, 1, 1, 1, 1, 2, 2, 2, 2, 2]);
+        assert_eq!(histogram.count, 3);
+        assert!((histogram.sum - 122.07).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_render_metrics() {
+        let mut summary = Summary::new("20180622");
+        let path = |path: &str| PathInfo{ repo_type: RepoType::Dam, path: path.to_string(), last_modified: None };
+        summary.add(&Entry::new(&path("/gato/a.gif"), Action::Exported, Some(100)));
+        summary.add(&Entry::new(&path("/tx/b.gif"), Action::Linked, Some(50)));
+        summary.finish(Duration::from_secs(5));
+        let mut metrics = Metrics::new();
+        metrics.request("host1:8443", "export", Duration::from_millis(200));
+        let mut worker = Metrics::new();
+        worker.request("host1:8443", "export", Duration::from_millis(20));
+        worker.fetch_error("host1:8443", &FetchError::LostSession{ error: "302 Found".to_string() });
+        worker.session_renewal("host1:8443");
+        metrics.merge(worker);
+        let text = metrics.render(&summary, Some(1529676000));
+        for line in &[
+            "pagers_last_success_timestamp_seconds 1529676000",
+            "pagers_last_run_status 0",
+            "pagers_nodes{repo=\"dam\",action=\"exported\"} 1",
+            "pagers_nodes{repo=\"dam\",action=\"linked\"} 1",
+            "pagers_written_bytes{repo=\"dam\"} 100",
+            "pagers_fetch_errors{backend=\"host1:8443\",error=\"LostSession\"} 1",
+            "pagers_fetch_errors{backend=\"host1:8443\",error=\"BackOff\"} 0",
+            "pagers_session_renewals{backend=\"host1:8443\"} 1",
+            "pagers_request_duration_seconds_bucket{backend=\"host1:8443\",request=\"export\",le=\"0.05\"} 1",
+            "pagers_request_duration_seconds_bucket{backend=\"host1:8443\",request=\"export\",le=\"0.25\"} 2",
+            "pagers_request_duration_seconds_count{backend=\"host1:8443\",request=\"export\"} 2",
+        ] {
+            assert!(text.lines().any(|l| l == *line), "missing {}", line);
+        }
+        assert_eq!(last_success(&text), Some(1529676000));
+        assert_eq!(last_success(&metrics.render(&summary, None)), None);
+    }
+}
@@ -8,7 +8,7 @@ use repos::{RepoType, NodeType, FOLDER_NODE_TYPE};
 /// PathInfo structure holds the associated repo type,
 /// the absolute path of the node with regards to the repo,
 /// and the last time the node was modified.
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct PathInfo {
     pub repo_type: RepoType,
     pub path: String,
@@ -65,23 +65,19 @@ impl Node {
 
     // consumes Node to generate PathInfo
     fn path_info(&self, repo_type: RepoType, folders: bool) -> Option<PathInfo> {
-        if &self.node_type == repo_type.node_type() || (folders && &self.node_type == FOLDER_NODE_TYPE) {
+        if self.node_type == repo_type.node_type() || (folders && self.node_type == FOLDER_NODE_TYPE) {
             for property in &self.properties {
                 if property["name"] == "mgnl:lastModified" {
                     if let Value::Array(ref last_modifieds) = property["values"] {
-                        match last_modifieds.last() {
-                            Some(&Value::String(ref last_modified)) => {
-                                if let Ok(last_modified) = last_modified.parse::<DateTime<Local>>() {
-                                    return Some(PathInfo{ repo_type: repo_type, path: self.path.to_owned(), last_modified: Some(last_modified) });
-                                }
-                                ()
-                            },
-                            _ => (),
+                        if let Some(Value::String(last_modified)) = last_modifieds.last() {
+                            if let Ok(last_modified) = last_modified.parse::<DateTime<Local>>() {
+                                return Some(PathInfo{ repo_type, path: self.path.to_owned(), last_modified: Some(last_modified) });
+                            }
                         }
                     }
                 }
             }
-            return Some(PathInfo{ repo_type: repo_type, path: self.path.clone(), last_modified: None })
+            return Some(PathInfo{ repo_type, path: self.path.clone(), last_modified: None })
         }
         None
     }
@@ -101,7 +97,7 @@ impl Node {
                 }
             }
         }
-        if paths.len() > 0 {
+        if !paths.is_empty() {
             Some(paths)
         } else {
             None
@@ -161,7 +157,7 @@ impl Node {
                     }
                 }
             }
-            if paths.len() > 0 {
+            if !paths.is_empty() {
                 return Some(paths);
             } else {
                 return None;
@@ -174,9 +170,9 @@ impl Node {
         if let Some(mut path_info) = self.path_info(repo_type, true) {
             path_info.last_modified = self.max_last_modified(repo_type);
             paths.push(path_info);
-            return Some(paths);
+            Some(paths)
         } else {
-            return None;
+            None
         }
     }
 }
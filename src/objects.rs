@@ -0,0 +1,139 @@
+use std::io::{self, Write};
+use std::fs::{self, DirBuilder, File};
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::collections::HashSet;
+use rand;
+use backup::{self, Hashing};
+use compress::Compression;
+
+/// Object store within ARCHIVE_DIR shared by the snapshots of the objects
+/// layout, hidden so it is never taken for a snapshot.
+pub const OBJECTS: &str = ".objects";
+
+/// Object store like <ARCHIVE_DIR>/.objects
+pub fn dir(archive_dir: &str) -> String {
+    format!("{}/{}", archive_dir, OBJECTS)
+}
+
+/// Object holding an export by its SHA-256 and compression, fanned out by the
+/// first two digits of its hash like <ARCHIVE_DIR>/.objects/b8/b809…fb0.xml.zst
+pub fn object_file(archive_dir: &str, sha256: &str, compression: Compression) -> String {
+    format!("{}/{}/{}.xml{}", dir(archive_dir), sha256.get(..2).unwrap_or(sha256), sha256, compression.suffix())
+}
+
+/// Stored object of an export if there is one, whatever its compression
+pub fn find(archive_dir: &str, sha256: &str) -> Option<String> {
+    Compression::ALL.iter()
+        .map(|&compression| object_file(archive_dir, sha256, compression))
+        .find(|file| fs::metadata(file).is_ok())
+}
+
+/// Stream an export into the object store, compressed, returning its size and
+/// SHA-256 which name the object. Exports already stored are not stored again,
+/// and as with backup::write_file nothing is left behind should copy fail.
+pub fn put<F>(archive_dir: &str, compression: Compression, copy: F) -> io::Result<(u64, String)>
+    where F: FnOnce(&mut dyn Write) -> io::Result<u64>
+{
+    let objects = dir(archive_dir);
+    DirBuilder::new().recursive(true).create(&objects)?;
+    let temp_file = backup::temp_filename(&format!("{}/{:016x}", objects, rand::random::<u64>()));
+    let result = File::create(&temp_file).and_then(|temp| {
+        let mut sha256 = String::new();
+        let size = compression.write(&temp, |out| {
+            let mut hashing = Hashing::new(out);
+            let size = copy(&mut hashing)?;
+            sha256 = hashing.sha256();
+            Ok(size)
+        })?;
+        temp.sync_all()?;
+        drop(temp);
+        if find(archive_dir, &sha256).is_some() {
+            fs::remove_file(&temp_file)?;
+        } else {
+            let file = object_file(archive_dir, &sha256, compression);
+            DirBuilder::new().recursive(true).create(format!("{}/{}", objects, &sha256[..2]))?;
+            fs::rename(&temp_file, file)?;
+        }
+        Ok((size, sha256))
+    });
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_file);
+    }
+    result
+}
+
+/// Remove the objects none of referenced, the SHA-256 of every export still
+/// used by a snapshot, returning how many objects and bytes were or, on a dry
+/// run, would be reclaimed.
+pub fn collect(archive_dir: &str, referenced: &HashSet<String>, dry_run: bool) -> io::Result<(usize, u64)> {
+    let objects = dir(archive_dir);
+    if !Path::new(&objects).is_dir() {
+        return Ok((0, 0));
+    }
+    let (mut removed, mut reclaimed) = (0, 0);
+    for fan_out in fs::read_dir(&objects)? {
+        let fan_out = fan_out?;
+        if !fan_out.file_type()?.is_dir() {
+            continue;
+        }
+        for object in fs::read_dir(fan_out.path())? {
+            let object = object?;
+            let name = object.file_name().to_string_lossy().into_owned();
+            let sha256 = name.split('.').next().unwrap_or_default();
+            if referenced.contains(sha256) {
+                continue;
+            }
+            reclaimed += object.metadata()?.blocks() * 512;
+            removed += 1;
+            if !dry_run {
+                fs::remove_file(object.path())?;
+            }
+        }
+    }
+    Ok((removed, reclaimed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use backup::test_dir;
+    use compress;
+
+    #[test]
+    fn test_put_once() {
+        let dir = test_dir("objects");
+        let (size, sha256) = put(&dir, Compression::Zstd, |out| io::copy(&mut "<sv:node/>".as_bytes(), out)).unwrap();
+        assert_eq!((size, sha256.as_str()), (10, "b80936834c3322f32424fe8a5b4df5732e10a0dced6b7de2c7003d82a7115fb0"));
+        let file = object_file(&dir, &sha256, Compression::Zstd);
+        assert_eq!(file, format!("{}/.objects/b8/{}.xml.zst", dir, sha256));
+        let mut export = String::new();
+        compress::open(&file).unwrap().read_to_string(&mut export).unwrap();
+        assert_eq!(export, "<sv:node/>");
+        // the same export is found stored whatever it is compressed with now
+        assert_eq!(put(&dir, Compression::None, |out| io::copy(&mut "<sv:node/>".as_bytes(), out)).unwrap(), (10, sha256.clone()));
+        assert_eq!(find(&dir, &sha256), Some(file));
+        assert!(fs::metadata(object_file(&dir, &sha256, Compression::None)).is_err());
+        assert!(put(&dir, Compression::None, |_| Err(io::Error::other("connection reset"))).is_err());
+        assert_eq!(fs::read_dir(super::dir(&dir)).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_collect() {
+        let dir = test_dir("collect");
+        let (_, kept) = put(&dir, Compression::None, |out| io::copy(&mut "<sv:node/>".as_bytes(), out)).unwrap();
+        let (_, unused) = put(&dir, Compression::Gzip, |out| io::copy(&mut "<sv:nope/>".as_bytes(), out)).unwrap();
+        let referenced: HashSet<String> = vec![kept.clone()].into_iter().collect();
+        assert_eq!(collect(&dir, &referenced, true).unwrap().0, 1);
+        assert!(find(&dir, &unused).is_some());
+        assert_eq!(collect(&dir, &referenced, false).unwrap().0, 1);
+        assert!(find(&dir, &unused).is_none());
+        assert!(find(&dir, &kept).is_some());
+        fs::remove_dir_all(&dir).unwrap();
+        let empty = test_dir("collect-empty");
+        assert_eq!(collect(&empty, &referenced, false).unwrap(), (0, 0));
+        fs::remove_dir_all(&empty).unwrap();
+    }
+}
@@ -0,0 +1,273 @@
+use std::io::{self, Read, Write};
+use std::fs::{self, File};
+use std::path::{Component, Path};
+use std::collections::HashMap;
+use failure::{Error, err_msg};
+use chrono::Local;
+use serde_json;
+use tar::{self, EntryType, Header};
+use repos::RepoType;
+use manifest::{self, Entry};
+use snapshot;
+use backup;
+use compress::{self, Compression};
+use verify;
+
+/// Part of a snapshot to pack, all of it unless narrowed to a repo or one site of it
+#[derive(Debug, Default, Clone)]
+pub struct Selection {
+    pub repo: Option<RepoType>,
+    pub site: Option<String>,
+}
+
+impl Selection {
+    pub fn is_all(&self) -> bool {
+        self.repo.is_none() && self.site.is_none()
+    }
+
+    fn contains(&self, entry: &Entry) -> bool {
+        self.repo.is_none_or(|repo| entry.repo == repo) && self.site.as_ref().is_none_or(|site| entry.site == *site)
+    }
+}
+
+/// Roll the selected part of a completed snapshot into a tar file, compressed
+/// per its suffix such as .tar.zst, holding every archived export in the files
+/// layout <ARCHIVE_EXT>/<repo>/<site>/<encoded>.xml whatever the layout of the
+/// snapshot, followed by the manifest of the selected nodes and the completion
+/// marker last. Returns the number of exports packed.
+pub fn pack(archive_dir: &str, archive_ext: &str, selection: &Selection, file: &str) -> Result<usize, Error> {
+    if !snapshot::is_complete(archive_dir, archive_ext) {
+        return Err(err_msg(format!("Snapshot {} was NOT completed", archive_ext)));
+    }
+    let layout = snapshot::layout(archive_dir, archive_ext)?;
+    let entries: Vec<Entry> = manifest::read::<Entry>(&snapshot::manifest_file(archive_dir, archive_ext))?.into_iter()
+        .filter(|entry| selection.contains(entry))
+        .collect();
+    let mut packed = 0;
+    backup::write_file_with(file, None, |temp| Compression::of(file).write(temp, |out| {
+        let mut builder = tar::Builder::new(out);
+        // an object shared by nodes last modified at the same time is packed once
+        // and hard linked, as unpacked files share their modified time
+        let mut members: HashMap<(String, i64), String> = HashMap::new();
+        for entry in entries.iter().filter(|entry| entry.action.is_archived()) {
+            let path = entry.path_info();
+            let archived = verify::archive_file(archive_dir, archive_ext, layout, entry)
+                .filter(|archived| fs::metadata(archived).is_ok())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("Missing {} {}", entry.repo, entry.path)))?;
+            let data = File::open(&archived)?;
+            let metadata = data.metadata()?;
+            let mtime = entry.last_modified.map(|last_modified| last_modified.timestamp())
+                .unwrap_or_else(|| backup::modified(&archived).map_or(0, |modified| modified.timestamp()));
+            // the archive path of the node relative to archive dir
+            let archive_path = backup::archive_path(archive_dir, archive_ext, &path);
+            let member = format!("{}/{}", &archive_path[archive_dir.len() + 1..], backup::backup_filename(&path, Compression::of(&archived)));
+            let mut header = Header::new_gnu();
+            header.set_mode(0o644);
+            header.set_mtime(mtime.max(0) as u64);
+            match members.get(&(archived.clone(), mtime)) {
+                Some(target) => {
+                    header.set_entry_type(EntryType::Link);
+                    header.set_size(0);
+                    builder.append_link(&mut header, &member, target)?;
+                },
+                None => {
+                    header.set_entry_type(EntryType::Regular);
+                    header.set_size(metadata.len());
+                    builder.append_data(&mut header, &member, data)?;
+                    members.insert((archived, mtime), member);
+                },
+            }
+            packed += 1;
+        }
+        let mut manifest = Vec::new();
+        for entry in &entries {
+            serde_json::to_writer(&mut manifest, entry)?;
+            manifest.push(b'\n');
+        }
+        append_bytes(&mut builder, archive_ext, snapshot::MANIFEST, &manifest)?;
+        // deletions and the summary only describe the whole snapshot
+        let snapshot_dir = snapshot::dir(archive_dir, archive_ext);
+        if selection.is_all() {
+            for name in &[snapshot::DELETIONS, snapshot::SUMMARY] {
+                match fs::read(format!("{}/{}", snapshot_dir, name)) {
+                    Ok(data) => append_bytes(&mut builder, archive_ext, name, &data)?,
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        append_bytes(&mut builder, archive_ext, snapshot::COMPLETE, &fs::read(format!("{}/{}", snapshot_dir, snapshot::COMPLETE))?)?;
+        builder.into_inner()?;
+        Ok(packed as u64)
+    }))?;
+    Ok(packed)
+}
+
+// add a file of the snapshot itself, such as its manifest, from data
+fn append_bytes<W: Write>(builder: &mut tar::Builder<W>, archive_ext: &str, name: &str, data: &[u8]) -> io::Result<()> {
+    let mut header = Header::new_gnu();
+    header.set_entry_type(EntryType::Regular);
+    header.set_mode(0o644);
+    header.set_mtime(Local::now().timestamp() as u64);
+    header.set_size(data.len() as u64);
+    builder.append_data(&mut header, format!("{}/{}", archive_ext, name), data)
+}
+
+/// Unpack a tar file made by pack, decompressed per its suffix, into a new
+/// snapshot of archive dir returning its name and the number of exports
+/// unpacked. The completion marker is written last so a snapshot that was
+/// not fully unpacked is never used as a previous snapshot.
+pub fn unpack(archive_dir: &str, file: &str) -> Result<(String, usize), Error> {
+    let mut archive = tar::Archive::new(compress::open(file)?);
+    let mut archive_ext: Option<String> = None;
+    let mut complete = None;
+    let mut unpacked = 0;
+    for member in archive.entries()? {
+        let mut member = member?;
+        let path = member.path()?.into_owned();
+        let ext = match path.components().next() {
+            Some(Component::Normal(ext)) => ext.to_string_lossy().into_owned(),
+            _ => return Err(err_msg(format!("{} is not within a snapshot", path.display()))),
+        };
+        match archive_ext {
+            Some(ref archive_ext) if *archive_ext != ext => return Err(err_msg(format!("{} is not within snapshot {}", path.display(), archive_ext))),
+            Some(_) => (),
+            None => {
+                if ext.starts_with('.') {
+                    return Err(err_msg(format!("{} is not a snapshot", ext)));
+                }
+                if Path::new(&snapshot::dir(archive_dir, &ext)).exists() {
+                    return Err(err_msg(format!("Snapshot {} already exists", ext)));
+                }
+                archive_ext = Some(ext.clone());
+            },
+        }
+        if path == Path::new(&ext).join(snapshot::COMPLETE) {
+            let mut marker = Vec::new();
+            member.read_to_end(&mut marker)?;
+            complete = Some(marker);
+            continue;
+        }
+        // refuses paths leading outside of archive dir
+        if !member.unpack_in(archive_dir)? {
+            return Err(err_msg(format!("{} is not within a snapshot", path.display())));
+        }
+        // exports are the members within <ARCHIVE_EXT>/<repo>/<site>
+        if path.components().count() == 4 {
+            unpacked += 1;
+        }
+    }
+    let archive_ext = archive_ext.ok_or_else(|| err_msg("No snapshot to unpack"))?;
+    match complete {
+        Some(marker) => {
+            let mut file = File::create(format!("{}/{}", snapshot::dir(archive_dir, &archive_ext), snapshot::COMPLETE))?;
+            file.write_all(&marker)?;
+            file.sync_all()?;
+        },
+        None => return Err(err_msg(format!("Snapshot {} was NOT completed", archive_ext))),
+    }
+    Ok((archive_ext, unpacked))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::MetadataExt;
+    use chrono::TimeZone;
+    use manifest::Action;
+    use nodes::PathInfo;
+    use snapshot::Layout;
+    use objects;
+    use backup::test_dir;
+
+    fn node(path: &str, timestamp: i64) -> PathInfo {
+        PathInfo{ repo_type: RepoType::Dam, path: path.to_string(), last_modified: Some(Local.timestamp_opt(timestamp, 0).unwrap()) }
+    }
+
+    fn finish(dir: &str, ext: &str, entries: &[Entry]) {
+        let mut writer = manifest::Writer::create(&snapshot::manifest_file(dir, ext)).unwrap();
+        for entry in entries {
+            writer.write(entry).unwrap();
+        }
+        writer.finish().unwrap();
+        snapshot::complete(dir, ext).unwrap();
+    }
+
+    #[test]
+    fn test_pack_unpack() {
+        let dir = test_dir("pack");
+        let a = node("/gato/a.gif", 1_500_000_000);
+        let b = node("/tx/b b.gif", 1_600_000_000);
+        let mut entries = Vec::new();
+        for (path, compression) in &[(&a, Compression::None), (&b, Compression::Zstd)] {
+            let archive_path = backup::archive_path(&dir, "20180622", path);
+            fs::create_dir_all(&archive_path).unwrap();
+            let file = format!("{}/{}", archive_path, backup::backup_filename(path, *compression));
+            backup::write_file_with(&file, path.last_modified.as_ref(), |temp| compression.write(temp, |out| io::copy(&mut "<sv:node/>".as_bytes(), out))).unwrap();
+            entries.push(Entry::new(path, Action::Exported, Some(10)));
+        }
+        entries.push(Entry::failed(&node("/tx/c.gif", 0), "timed out"));
+        finish(&dir, "20180622", &entries);
+        fs::write(snapshot::summary_file(&dir, "20180622"), "{}").unwrap();
+
+        let file = format!("{}/20180622.tar.zst", dir);
+        assert_eq!(pack(&dir, "20180622", &Selection::default(), &file).unwrap(), 2);
+        let restored = format!("{}/restored", dir);
+        fs::create_dir(&restored).unwrap();
+        assert_eq!(unpack(&restored, &file).unwrap(), ("20180622".to_string(), 2));
+        assert!(snapshot::is_complete(&restored, "20180622"));
+        let complete = |dir: &str| fs::read(format!("{}/20180622/{}", dir, snapshot::COMPLETE)).unwrap();
+        assert_eq!(complete(&restored), complete(&dir));
+        assert_eq!(manifest::read::<Entry>(&snapshot::manifest_file(&restored, "20180622")).unwrap(), entries);
+        assert_eq!(fs::read_to_string(snapshot::summary_file(&restored, "20180622")).unwrap(), "{}");
+        for path in &[&a, &b] {
+            let file = backup::archived_file(&backup::archive_path(&restored, "20180622", path), path).unwrap();
+            assert_eq!(backup::sha256_file(&file).unwrap().0, 10);
+            assert_eq!(backup::modified(&file), path.last_modified);
+        }
+        assert!(unpack(&restored, &file).unwrap_err().to_string().contains("already exists"));
+
+        // only the selected site and its manifest entries are packed
+        let selection = Selection{ repo: Some(RepoType::Dam), site: Some("tx".to_string()) };
+        let file = format!("{}/tx.tar", dir);
+        assert_eq!(pack(&dir, "20180622", &selection, &file).unwrap(), 1);
+        let restored = format!("{}/tx", dir);
+        fs::create_dir(&restored).unwrap();
+        assert_eq!(unpack(&restored, &file).unwrap().1, 1);
+        assert_eq!(manifest::read::<Entry>(&snapshot::manifest_file(&restored, "20180622")).unwrap().len(), 2);
+        assert!(fs::metadata(format!("{}/20180622/dam/gato", restored)).is_err());
+        assert!(fs::metadata(snapshot::summary_file(&restored, "20180622")).is_err());
+
+        snapshot::incomplete(&dir, "20180622").unwrap();
+        assert!(pack(&dir, "20180622", &Selection::default(), &format!("{}/incomplete.tar", dir)).is_err());
+        assert!(fs::metadata(format!("{}/incomplete.tar", dir)).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pack_objects() {
+        let dir = test_dir("pack-objects");
+        fs::create_dir_all(snapshot::dir(&dir, "20180622")).unwrap();
+        snapshot::set_layout(&dir, "20180622", Layout::Objects).unwrap();
+        let (size, sha256) = objects::put(&dir, Compression::Gzip, |out| io::copy(&mut "<sv:node/>".as_bytes(), out)).unwrap();
+        // the same export of three nodes, two of them last modified at the same time
+        let paths = [node("/gato/a.gif", 1_500_000_000), node("/tx/a.gif", 1_500_000_000), node("/tx/b.gif", 1_600_000_000)];
+        let entries: Vec<Entry> = paths.iter().map(|path| Entry::new(path, Action::Linked, Some(size)).with_sha256(sha256.clone())).collect();
+        finish(&dir, "20180622", &entries);
+
+        let file = format!("{}/20180622.tar", dir);
+        assert_eq!(pack(&dir, "20180622", &Selection::default(), &file).unwrap(), 3);
+        let restored = format!("{}/restored", dir);
+        fs::create_dir(&restored).unwrap();
+        assert_eq!(unpack(&restored, &file).unwrap().1, 3);
+        assert_eq!(snapshot::layout(&restored, "20180622").unwrap(), Layout::Files);
+        let files: Vec<String> = paths.iter().map(|path| format!("{}/{}", backup::archive_path(&restored, "20180622", path), backup::backup_filename(path, Compression::Gzip))).collect();
+        for (path, file) in paths.iter().zip(&files) {
+            assert_eq!(backup::sha256_file(file).unwrap(), (size, sha256.clone()));
+            assert_eq!(backup::modified(file), path.last_modified);
+        }
+        assert_eq!(fs::metadata(&files[0]).unwrap().ino(), fs::metadata(&files[1]).unwrap().ino());
+        assert_eq!(fs::metadata(&files[2]).unwrap().nlink(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
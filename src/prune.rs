@@ -0,0 +1,196 @@
+use std::io;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::collections::HashSet;
+use chrono::{NaiveDate, Datelike};
+use snapshot::{self, Layout};
+use manifest::{self, Entry};
+
+/// Grandfather-father-son retention policy. The newest snapshot of each of
+/// the last daily days, weekly ISO weeks, and monthly months is retained.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Policy {
+    pub daily: usize,
+    pub weekly: usize,
+    pub monthly: usize,
+}
+
+/// Period of time such as a day, week or month that a date falls within
+type Period = fn(&NaiveDate) -> (i32, u32);
+
+/// Snapshots to keep from a list of (snapshot, date) ordered newest first.
+/// The newest snapshot is always kept.
+pub fn retain(snapshots: &[(String, NaiveDate)], policy: &Policy) -> HashSet<String> {
+    let mut keep = HashSet::new();
+    if let Some((newest, _)) = snapshots.first() {
+        keep.insert(newest.clone());
+    }
+    let periods: [(usize, Period); 3] = [
+        (policy.daily, |d| (d.year(), d.ordinal())),
+        (policy.weekly, |d| (d.iso_week().year(), d.iso_week().week())),
+        (policy.monthly, |d| (d.year(), d.month())),
+    ];
+    for &(count, period) in periods.iter() {
+        let mut last = None;
+        let mut kept = 0;
+        for (ext, date) in snapshots {
+            if kept >= count {
+                break;
+            }
+            if last != Some(period(date)) {
+                last = Some(period(date));
+                keep.insert(ext.clone());
+                kept += 1;
+            }
+        }
+    }
+    keep
+}
+
+/// Determine which snapshots within archive dir are to be pruned, oldest first.
+/// Only snapshots named by date are considered. Completed snapshots are pruned
+/// per the policy, while incomplete snapshots are pruned only when older than
+/// the newest completed snapshot as newer ones may still be running.
+pub fn plan(archive_dir: &str, policy: &Policy) -> io::Result<Vec<String>> {
+    let snapshots: Vec<(String, NaiveDate)> = snapshot::list(archive_dir)?.into_iter()
+        .filter_map(|ext| snapshot::date(&ext).map(|date| (ext, date)))
+        .collect();
+    // listed oldest to newest by date, see snapshot::order
+    let mut complete: Vec<(String, NaiveDate)> = snapshots.iter()
+        .filter(|&(ext, _)| snapshot::is_complete(archive_dir, ext))
+        .cloned()
+        .collect();
+    complete.reverse();
+    let newest = match complete.first() {
+        Some((newest, _)) => newest.clone(),
+        None => return Ok(Vec::new()),
+    };
+    let keep = retain(&complete, policy);
+    Ok(snapshots.into_iter()
+        .map(|(ext, _)| ext)
+        .filter(|ext| !keep.contains(ext) && (snapshot::is_complete(archive_dir, ext) || snapshot::order(ext) < snapshot::order(&newest)))
+        .collect())
+}
+
+/// Remove a directory tree returning the bytes actually reclaimed. As snapshots
+/// share files through hard links only files whose last link is removed count.
+pub fn remove(dir: &Path) -> io::Result<u64> {
+    let mut reclaimed = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let meta = entry.metadata()?;
+        if meta.is_dir() {
+            reclaimed += remove(&entry.path())?;
+        } else {
+            if meta.nlink() == 1 {
+                reclaimed += meta.blocks() * 512;
+            }
+            fs::remove_file(entry.path())?;
+        }
+    }
+    fs::remove_dir(dir)?;
+    Ok(reclaimed)
+}
+
+/// SHA-256 of the exports still used by the snapshots of the objects layout
+/// within archive dir other than those pruned, so the object store can be
+/// collected. Snapshots without a manifest have yet to use any.
+pub fn referenced(archive_dir: &str, pruned: &[String]) -> io::Result<HashSet<String>> {
+    let mut referenced = HashSet::new();
+    for ext in snapshot::list(archive_dir)?.into_iter().filter(|ext| !pruned.contains(ext)) {
+        if snapshot::layout(archive_dir, &ext)? != Layout::Objects {
+            continue;
+        }
+        let entries = match manifest::read_partial::<Entry>(&snapshot::manifest_file(archive_dir, &ext)) {
+            Ok(entries) => entries,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e),
+        };
+        referenced.extend(entries.into_iter().filter(|entry| entry.action.is_archived()).filter_map(|entry| entry.sha256));
+    }
+    Ok(referenced)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backup::test_dir;
+
+    fn dated(exts: &[&str]) -> Vec<(String, NaiveDate)> {
+        exts.iter().map(|ext| (ext.to_string(), snapshot::date(ext).unwrap())).collect()
+    }
+
+    #[test]
+    fn test_retain_daily_weekly_monthly() {
+        // newest first: Wed 2018-06-27 back through Thu 2018-04-26
+        let snapshots = dated(&["20180627", "20180626", "20180625", "20180624", "20180617", "20180610", "20180531", "20180430", "20180426"]);
+        let policy = Policy{ daily: 2, weekly: 2, monthly: 3 };
+        let mut keep: Vec<String> = retain(&snapshots, &policy).into_iter().collect();
+        keep.sort();
+        assert_eq!(keep, vec!["20180430", "20180531", "20180624", "20180626", "20180627"]);
+    }
+
+    #[test]
+    fn test_retain_newest() {
+        let snapshots = dated(&["20180627", "20180626"]);
+        let policy = Policy{ daily: 0, weekly: 0, monthly: 0 };
+        assert_eq!(retain(&snapshots, &policy).into_iter().collect::<Vec<String>>(), vec!["20180627"]);
+    }
+
+    #[test]
+    fn test_plan() {
+        let dir = test_dir("plan");
+        for ext in &["20180620", "20180621", "20180622", "20180623", "20180624", "manual"] {
+            fs::create_dir(format!("{}/{}", dir, ext)).unwrap();
+        }
+        for ext in &["20180620", "20180622", "20180623", "manual"] {
+            snapshot::complete(&dir, ext).unwrap();
+        }
+        let policy = Policy{ daily: 2, weekly: 0, monthly: 0 };
+        assert_eq!(plan(&dir, &policy).unwrap(), vec!["20180620", "20180621"]);
+
+        // the newest snapshot is found by date whichever way snapshots are named
+        for ext in &["2018-06-25-Mon", "2018-06-26-Tue"] {
+            fs::create_dir(format!("{}/{}", dir, ext)).unwrap();
+        }
+        snapshot::complete(&dir, "2018-06-25-Mon").unwrap();
+        let policy = Policy{ daily: 1, weekly: 0, monthly: 0 };
+        assert_eq!(plan(&dir, &policy).unwrap(), vec!["20180620", "20180621", "20180622", "20180623", "20180624"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_remove_counts_last_links() {
+        let dir = test_dir("remove");
+        fs::create_dir_all(format!("{}/20180621/dam/gato", dir)).unwrap();
+        fs::create_dir_all(format!("{}/20180622/dam/gato", dir)).unwrap();
+        fs::write(format!("{}/20180621/dam/gato/shared.xml", dir), vec![b'x'; 8192]).unwrap();
+        fs::write(format!("{}/20180621/dam/gato/removed.xml", dir), vec![b'x'; 8192]).unwrap();
+        fs::hard_link(format!("{}/20180621/dam/gato/shared.xml", dir), format!("{}/20180622/dam/gato/shared.xml", dir)).unwrap();
+        let removed = fs::metadata(format!("{}/20180621/dam/gato/removed.xml", dir)).unwrap().blocks() * 512;
+        assert_eq!(remove(Path::new(&format!("{}/20180621", dir))).unwrap(), removed);
+        assert!(fs::metadata(format!("{}/20180621", dir)).is_err());
+        assert!(fs::metadata(format!("{}/20180622/dam/gato/shared.xml", dir)).is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_referenced() {
+        let dir = test_dir("referenced");
+        for (ext, sha256) in &[("20180621", "aa"), ("20180622", "bb"), ("20180623", "cc")] {
+            fs::create_dir(format!("{}/{}", dir, ext)).unwrap();
+            snapshot::set_layout(&dir, ext, Layout::Objects).unwrap();
+            fs::write(snapshot::manifest_file(&dir, ext), format!(r#"{{"repo":"dam","site":"gato","path":"/gato/a.gif","last_modified":null,"size":1,"sha256":"{}","action":"linked","error":null}}"#, sha256)).unwrap();
+        }
+        // files snapshots and failed entries use no objects
+        snapshot::set_layout(&dir, "20180623", Layout::Files).unwrap();
+        fs::write(snapshot::manifest_file(&dir, "20180622"), r#"{"repo":"dam","site":"gato","path":"/gato/a.gif","last_modified":null,"size":null,"sha256":"dd","action":"failed","error":"timed out"}"#).unwrap();
+        fs::create_dir(format!("{}/20180624", dir)).unwrap();
+        snapshot::set_layout(&dir, "20180624", Layout::Objects).unwrap();
+        let referenced = referenced(&dir, &["20180621".to_string()]).unwrap();
+        assert!(referenced.is_empty());
+        assert_eq!(super::referenced(&dir, &[]).unwrap().into_iter().collect::<Vec<String>>(), vec!["aa"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
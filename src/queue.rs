@@ -0,0 +1,224 @@
+use std::thread;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+use std::collections::VecDeque;
+use crossbeam_channel::{Receiver, RecvTimeoutError, Sender, SendTimeoutError};
+use nodes::PathInfo;
+use retry::Attempts;
+use shutdown;
+
+const POLL: Duration = Duration::from_millis(100);
+
+/// Path to be exported along with the attempts made so far
+#[derive(Debug)]
+pub struct Task {
+    pub path: PathInfo,
+    pub attempts: Option<Attempts>,
+    pub deferred: bool,
+}
+
+#[derive(Debug, Default)]
+struct Pending {
+    requeued: VecDeque<Task>,
+    deferred: Vec<Task>,
+    in_flight: usize,
+    dispatched: bool,
+}
+
+/// Work shared by all workers: paths dispatched while listing, paths requeued
+/// by workers whose backend became unhealthy, and once all paths have been
+/// dispatched those deferred to be revisited at the end of the run.
+/// Every task handed out must be given back through done, requeue, or defer.
+#[derive(Debug)]
+pub struct Queue {
+    dispatch: Receiver<PathInfo>,
+    pending: Mutex<Pending>,
+    workers: AtomicUsize,
+}
+
+/// Held by a worker while taking tasks, the worker has quit once dropped
+/// even if it panicked.
+#[derive(Debug)]
+pub struct Attached<'a> {
+    queue: &'a Queue,
+}
+
+impl Queue {
+    /// Queue taken up by the given number of workers, each of which attaches
+    pub fn new(dispatch: Receiver<PathInfo>, workers: usize) -> Queue {
+        Queue{ dispatch, pending: Mutex::new(Pending::default()), workers: AtomicUsize::new(workers) }
+    }
+
+    pub fn attach(&self) -> Attached<'_> {
+        Attached{ queue: self }
+    }
+
+    /// Dispatch a path to the workers, waiting for room if needed. The path is
+    /// given back when every worker has quit or a shutdown is requested.
+    pub fn dispatch(&self, s: &Sender<PathInfo>, path: PathInfo) -> Result<(), PathInfo> {
+        let mut path = path;
+        loop {
+            if shutdown::is_requested() || self.workers.load(Ordering::SeqCst) == 0 {
+                return Err(path);
+            }
+            match s.send_timeout(path, POLL) {
+                Ok(()) => return Ok(()),
+                Err(SendTimeoutError::Timeout(unsent)) | Err(SendTimeoutError::Disconnected(unsent)) => path = unsent,
+            }
+        }
+    }
+
+    /// Next task, waiting for one if needed. None once every path has been
+    /// dispatched and no other task is pending or in flight, or once a
+    /// shutdown is requested.
+    pub fn next(&self) -> Option<Task> {
+        loop {
+            if shutdown::is_requested() {
+                return None;
+            }
+            let dispatched = {
+                let mut pending = self.pending.lock().unwrap();
+                if let Some(task) = pending.requeued.pop_front() {
+                    pending.in_flight += 1;
+                    return Some(task);
+                }
+                if pending.dispatched {
+                    if let Some(task) = pending.deferred.pop() {
+                        pending.in_flight += 1;
+                        return Some(task);
+                    }
+                    if pending.in_flight == 0 {
+                        return None;
+                    }
+                }
+                pending.dispatched
+            };
+            if dispatched {
+                // tasks in flight may yet be requeued or deferred
+                thread::sleep(POLL);
+                continue;
+            }
+            match self.dispatch.recv_timeout(POLL) {
+                Ok(path) => {
+                    self.pending.lock().unwrap().in_flight += 1;
+                    return Some(Task{ path, attempts: None, deferred: false });
+                },
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => self.pending.lock().unwrap().dispatched = true,
+            }
+        }
+    }
+
+    pub fn done(&self) {
+        self.pending.lock().unwrap().in_flight -= 1;
+    }
+
+    /// Hand a task back for another worker to take up right away
+    pub fn requeue(&self, task: Task) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.requeued.push_back(task);
+        pending.in_flight -= 1;
+    }
+
+    /// Hand a task back to be revisited once all paths have been dispatched
+    pub fn defer(&self, mut task: Task) {
+        task.deferred = true;
+        let mut pending = self.pending.lock().unwrap();
+        pending.deferred.push(task);
+        pending.in_flight -= 1;
+    }
+
+    /// Whether every path has been dispatched and done
+    pub fn is_finished(&self) -> bool {
+        let pending = self.pending.lock().unwrap();
+        pending.dispatched && pending.requeued.is_empty() && pending.deferred.is_empty() && pending.in_flight == 0
+    }
+
+    /// Take every path not yet done once the workers have quit
+    pub fn drain(&self) -> Vec<PathInfo> {
+        let mut pending = self.pending.lock().unwrap();
+        let mut paths: Vec<PathInfo> = pending.requeued.drain(..).map(|task| task.path).collect();
+        paths.extend(self.dispatch.try_iter());
+        paths.extend(pending.deferred.drain(..).map(|task| task.path));
+        paths
+    }
+}
+
+impl<'a> Drop for Attached<'a> {
+    fn drop(&mut self) {
+        self.queue.workers.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel as channel;
+    use repos::RepoType;
+
+    fn path(path: &str) -> PathInfo {
+        PathInfo{ repo_type: RepoType::Dam, path: path.to_string(), last_modified: None }
+    }
+
+    #[test]
+    fn test_queue_order() {
+        let (s, r) = channel::unbounded();
+        let queue = Queue::new(r, 1);
+        s.send(path("/gato/a.gif")).unwrap();
+        s.send(path("/gato/b.gif")).unwrap();
+        let a = queue.next().unwrap();
+        queue.defer(a);
+        let b = queue.next().unwrap();
+        assert_eq!(b.path.path, "/gato/b.gif");
+        queue.requeue(b);
+        // requeued tasks come before newly dispatched ones
+        s.send(path("/gato/c.gif")).unwrap();
+        assert_eq!(queue.next().unwrap().path.path, "/gato/b.gif");
+        queue.done();
+        assert_eq!(queue.next().unwrap().path.path, "/gato/c.gif");
+        queue.done();
+        drop(s);
+        // deferred tasks wait until dispatching is over
+        let a = queue.next().unwrap();
+        assert_eq!(a.path.path, "/gato/a.gif");
+        assert!(a.deferred);
+        assert!(!queue.is_finished());
+        queue.done();
+        assert!(queue.next().is_none());
+        assert!(queue.is_finished());
+    }
+
+    #[test]
+    fn test_queue_waits_for_in_flight() {
+        let (s, r) = channel::unbounded();
+        let queue = Queue::new(r, 1);
+        s.send(path("/gato/a.gif")).unwrap();
+        drop(s);
+        let a = queue.next().unwrap();
+        thread::scope(|scope| {
+            let next = scope.spawn(|| queue.next());
+            thread::sleep(POLL * 2);
+            queue.requeue(a);
+            assert_eq!(next.join().unwrap().unwrap().path.path, "/gato/a.gif");
+        });
+        queue.done();
+        assert!(queue.next().is_none());
+    }
+
+    #[test]
+    fn test_queue_drain() {
+        let (s, r) = channel::bounded(1);
+        let queue = Queue::new(r, 1);
+        assert!(queue.dispatch(&s, path("/gato/a.gif")).is_ok());
+        let a = queue.next().unwrap();
+        queue.defer(a);
+        assert!(queue.dispatch(&s, path("/gato/b.gif")).is_ok());
+        let attached = queue.attach();
+        drop(attached);
+        // no worker is left to take up the path
+        assert_eq!(queue.dispatch(&s, path("/gato/c.gif")), Err(path("/gato/c.gif")));
+        let paths: Vec<String> = queue.drain().into_iter().map(|path| path.path).collect();
+        assert_eq!(paths, vec!["/gato/b.gif", "/gato/a.gif"]);
+    }
+}
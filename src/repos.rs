@@ -1,15 +1,17 @@
 use std::str::FromStr;
 use std::fmt::{self, Display};
 use serde_json::{self, Value};
+use serde::{de, Serialize, Serializer, Deserialize, Deserializer};
 use failure::{Error, err_msg};
+use retry::{self, Policy};
 
-pub const FOLDER_NODE_TYPE: &'static str = "mgnl:folder";
+pub const FOLDER_NODE_TYPE: &str = "mgnl:folder";
 
 macro_rules! lowercase_enum {
     (name $name: ident,
      $($str_name: expr => ($variant: ident, $str_node_type: expr),)+
     ) => {
-        #[derive(Debug, Copy, Clone, PartialEq)]
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
         pub enum $name {
             $($variant),+
         }
@@ -33,6 +35,18 @@ macro_rules! lowercase_enum {
             }
         }
 
+        impl Serialize for $name {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                serializer.collect_str(self)
+            }
+        }
+
+        impl<'de> Deserialize<'de> for $name {
+            fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                String::deserialize(deserializer)?.parse().map_err(de::Error::custom)
+            }
+        }
+
         pub trait NodeType {
             fn node_type(&self) -> &str;
         }
@@ -59,41 +73,115 @@ lowercase_enum!{
     "users" => (Users, "mgnl:user"), //"mgnl:folder"
 }
 
+impl RepoType {
+    /// Default export granularity of the repo. None exports every node of the
+    /// repo's node type, otherwise nodes are exported at the level within a site
+    /// where any nested nodes are rolled up into their parent export.
+    pub fn default_level(self) -> Option<usize> {
+        match self {
+            RepoType::Config => Some(2),
+            RepoType::Usergroups | RepoType::Userroles | RepoType::Users => Some(1),
+            _ => None,
+        }
+    }
+}
+
 pub type Site = String;
 
 pub type Sites = Vec<Site>;
 
+/// Repo to backup along with an optional whitelist of sites.
+/// When no sites are listed every site within the repo is backed up.
+/// See RepoType::default_level for the meaning of level, while exports
+/// limits how many of the repo's nodes are exported at once.
 #[derive(Debug, PartialEq)]
 pub struct Repo {
-    repo_type: RepoType,
-    sites: Option<Sites>,
+    pub repo_type: RepoType,
+    pub sites: Option<Sites>,
+    pub level: Option<usize>,
+    pub retry: Policy,
+    pub exports: Option<usize>,
+}
+
+impl Repo {
+    pub fn new(repo_type: RepoType, sites: Option<Sites>) -> Repo {
+        Repo{ repo_type, sites, level: repo_type.default_level(), retry: Policy::default(), exports: None }
+    }
+
+    /// Determine if site is to be backed up for this repo.
+    pub fn has_site(&self, site: &str) -> bool {
+        match self.sites {
+            Some(ref sites) => sites.iter().any(|s| s == site),
+            None => true,
+        }
+    }
 }
 
 pub type Repos = Vec<Repo>;
 
+fn new_sites(ss: Value) -> Result<Sites, Error> {
+    let mut sites: Sites = Vec::new();
+    if let Value::Array(ss) = ss {
+        for site in ss {
+            if let Value::String(site) = site {
+                 sites.push(site);
+            } else {
+                return Err(err_msg("Malformed repo site entry"));
+            }
+        }
+        Ok(sites)
+    } else {
+        Err(err_msg("Malformed repo site entry"))
+    }
+}
+
+/// Parse a JSON list of repos where each entry is either the repo name, an
+/// object of repo name to list of sites, or an object of repo name to an
+/// object with optional "sites", "level", "retry" (see retry::new), and "exports" entries:
+///   ["website", {"dam": {"sites": ["dam1"], "exports": 8}}, {"config": {"sites": ["modules"], "level": 3}}]
 pub fn new(data: &str) -> Result<Repos, Error> {
     let mut repos: Repos = Vec::new();
     let json = serde_json::from_str(data)?;
     if let Value::Array(repo_list) = json {
         for repo_json in repo_list {
             match repo_json {
-                Value::String(r) => repos.push(Repo{ repo_type: r.parse()?, sites: None }),
+                Value::String(r) => repos.push(Repo::new(r.parse()?, None)),
                 Value::Object(o) => {
                     for (repo, ss) in o {
                         let repo: RepoType = repo.parse()?;
-                        let mut sites: Sites = Vec::new();
-                        if let Value::Array(ss) = ss {
-                            for site in ss {
-                                if let Value::String(site) = site {
-                                     sites.push(site);
-                                } else {
-                                    return Err(err_msg("Malformed repo site entry"));
-                                }
+                        if let Value::Object(mut settings) = ss {
+                            let mut entry = Repo::new(repo, None);
+                            if let Some(ss) = settings.remove("sites") {
+                                entry.sites = Some(new_sites(ss)?);
+                            }
+                            match settings.remove("level") {
+                                Some(Value::Number(level)) => match level.as_u64() {
+                                    Some(level) => entry.level = Some(level as usize),
+                                    None => return Err(err_msg("Malformed repo level entry")),
+                                },
+                                Some(Value::Null) => entry.level = None,
+                                Some(_) => return Err(err_msg("Malformed repo level entry")),
+                                None => (),
                             }
+                            match settings.remove("exports") {
+                                Some(Value::Number(exports)) => match exports.as_u64() {
+                                    Some(exports) if exports > 0 => entry.exports = Some(exports as usize),
+                                    _ => return Err(err_msg("Malformed repo exports entry")),
+                                },
+                                Some(Value::Null) | None => (),
+                                Some(_) => return Err(err_msg("Malformed repo exports entry")),
+                            }
+                            if let Some(settings) = settings.remove("retry") {
+                                entry.retry = retry::new(settings)?;
+                            }
+                            // a misspelt setting would otherwise quietly keep its default
+                            if let Some(key) = settings.keys().next() {
+                                return Err(err_msg(format!("Unknown repo setting {}", key)));
+                            }
+                            repos.push(entry);
                         } else {
-                            return Err(err_msg("Malformed repo site entry"));
+                            repos.push(Repo::new(repo, Some(new_sites(ss)?)));
                         }
-                        repos.push(Repo{ repo_type: repo, sites: Some(sites) });
                     }
                 },
                 _ => return Err(err_msg("Invalid repo list entry type")),
@@ -130,8 +218,8 @@ mod tests {
         let json = r#"["dam","website"]"#;
         let repos: Repos = new(json).unwrap();
         assert_eq!(repos, vec![
-            Repo{ repo_type: RepoType::Dam, sites: None },
-            Repo{ repo_type: RepoType::Website, sites: None },
+            Repo{ repo_type: RepoType::Dam, sites: None, level: None, retry: Policy::default(), exports: None },
+            Repo{ repo_type: RepoType::Website, sites: None, level: None, retry: Policy::default(), exports: None },
         ]);
     }
 
@@ -140,8 +228,8 @@ mod tests {
         let json = r#"[{"dam": ["dam1","dam2"]}, {"website": ["website1"]}]"#;
         let repos: Repos = new(json).unwrap();
         assert_eq!(repos, vec![
-            Repo{ repo_type: RepoType::Dam, sites: Some(vec!["dam1".to_string(), "dam2".to_string()]) },
-            Repo{ repo_type: RepoType::Website, sites: Some(vec!["website1".to_string()]) },
+            Repo{ repo_type: RepoType::Dam, sites: Some(vec!["dam1".to_string(), "dam2".to_string()]), level: None, retry: Policy::default(), exports: None },
+            Repo{ repo_type: RepoType::Website, sites: Some(vec!["website1".to_string()]), level: None, retry: Policy::default(), exports: None },
         ]);
     }
 
@@ -150,9 +238,53 @@ mod tests {
         let json = r#"[{"dam": ["dam1","dam2"]}, "website"]"#;
         let repos: Repos = new(json).unwrap();
         assert_eq!(repos, vec![
-            Repo{ repo_type: RepoType::Dam, sites: Some(vec!["dam1".to_string(), "dam2".to_string()]) },
-            Repo{ repo_type: RepoType::Website, sites: None },
+            Repo{ repo_type: RepoType::Dam, sites: Some(vec!["dam1".to_string(), "dam2".to_string()]), level: None, retry: Policy::default(), exports: None },
+            Repo{ repo_type: RepoType::Website, sites: None, level: None, retry: Policy::default(), exports: None },
         ]);
     }
 
+    #[test]
+    fn test_repo_has_site() {
+        let json = r#"[{"dam": ["dam1","dam2"]}, "website"]"#;
+        let repos: Repos = new(json).unwrap();
+        assert!(repos[0].has_site("dam2"));
+        assert!(!repos[0].has_site("dam3"));
+        assert!(repos[1].has_site("anything"));
+    }
+
+    #[test]
+    fn test_default_repo_level() {
+        let json = r#"["users", "config", "website"]"#;
+        let repos: Repos = new(json).unwrap();
+        assert_eq!(repos, vec![
+            Repo{ repo_type: RepoType::Users, sites: None, level: Some(1), retry: Policy::default(), exports: None },
+            Repo{ repo_type: RepoType::Config, sites: None, level: Some(2), retry: Policy::default(), exports: None },
+            Repo{ repo_type: RepoType::Website, sites: None, level: None, retry: Policy::default(), exports: None },
+        ]);
+    }
+
+    #[test]
+    fn test_retry_settings_to_repo() {
+        let json = r#"[{"dam": {"retry": {"back_off": {"attempts": 5}, "budget": 8}}}]"#;
+        let repos: Repos = new(json).unwrap();
+        assert_eq!(repos[0].retry.back_off.attempts, 5);
+        assert_eq!(repos[0].retry.budget, 8);
+        assert_eq!(repos[0].retry.transport, Policy::default().transport);
+        assert!(new(r#"[{"dam": {"retry": {"budget": -1}}}]"#).is_err());
+        assert!(new(r#"[{"dam": {"retry": {"back_off": {"atempts": 5}}}}]"#).is_err());
+        assert!(new(r#"[{"dam": {"retry": {"budgte": 8}}}]"#).is_err());
+    }
+
+    #[test]
+    fn test_object_settings_to_repo() {
+        let json = r#"[{"config": {"sites": ["modules"], "level": 3}}, {"users": {"level": null}}, {"website": {"level": 2, "exports": 2}}]"#;
+        let repos: Repos = new(json).unwrap();
+        assert_eq!(repos, vec![
+            Repo{ repo_type: RepoType::Config, sites: Some(vec!["modules".to_string()]), level: Some(3), retry: Policy::default(), exports: None },
+            Repo{ repo_type: RepoType::Users, sites: None, level: None, retry: Policy::default(), exports: None },
+            Repo{ repo_type: RepoType::Website, sites: None, level: Some(2), retry: Policy::default(), exports: Some(2) },
+        ]);
+        assert!(new(r#"[{"dam": {"exports": 0}}]"#).is_err());
+        assert_eq!(new(r#"[{"config": {"levle": 3}}]"#).unwrap_err().to_string(), "Unknown repo setting levle");
+    }
 }
@@ -0,0 +1,130 @@
+use std::io::{self, Read};
+use nodes::PathInfo;
+use repos::RepoType;
+use manifest::{self, Entry};
+use snapshot::{self, Layout};
+use compress::{self, Compression};
+use backup;
+use objects;
+use storage::{self, Storage};
+
+/// Archived export of a node to be restored
+#[derive(Debug, PartialEq)]
+pub struct Restore {
+    /// Key of the export in storage, or its object when the snapshot has the objects layout
+    pub file: String,
+    pub path: PathInfo,
+    pub layout: Layout,
+}
+
+impl Restore {
+    /// Read the export, decompressed
+    pub fn open(&self, storage: &dyn Storage) -> io::Result<Box<dyn Read + Send>> {
+        match self.layout {
+            Layout::Files => storage::open(storage, &self.file),
+            Layout::Objects => compress::open(&self.file),
+        }
+    }
+}
+
+/// Determine which exports of a repo archived in storage for a snapshot, or objects
+/// when the snapshot has the objects layout, restore the node at path, or the whole
+/// site when path is a site. As each export holds the node's entire subtree, exports
+/// nested under another selected export are left out.
+pub fn plan(storage: &dyn Storage, archive_dir: &str, archive_ext: &str, repo_type: RepoType, path: &str) -> io::Result<Vec<Restore>> {
+    let path = format!("/{}", path.trim_matches('/'));
+    let mut restores = match snapshot::layout(archive_dir, archive_ext)? {
+        Layout::Files => files(storage, archive_ext, repo_type, &path)?,
+        Layout::Objects => stored(archive_dir, archive_ext, repo_type)?,
+    };
+    restores.retain(|restore| restore.path.path == path || restore.path.path.starts_with(&format!("{}/", path)));
+    restores.sort_by(|a, b| a.path.path.cmp(&b.path.path));
+    let mut top: Vec<Restore> = Vec::new();
+    for restore in restores {
+        let nested = top.iter().any(|t| restore.path.path.starts_with(&format!("{}/", t.path.path)));
+        if !nested {
+            top.push(restore);
+        }
+    }
+    Ok(top)
+}
+
+// exports archived in storage of the site of path
+fn files(storage: &dyn Storage, archive_ext: &str, repo_type: RepoType, path: &str) -> io::Result<Vec<Restore>> {
+    let site = PathInfo{ repo_type, path: path.to_string(), last_modified: None };
+    let mut restores = Vec::new();
+    for key in storage.list(&backup::archive_key(archive_ext, &site))? {
+        let filename = key.rsplit('/').next().unwrap_or_default();
+        if let Some(node_path) = backup::node_path(backup::extract_site(path), filename) {
+            restores.push(Restore{
+                path: PathInfo{ repo_type, path: node_path, last_modified: storage.modified(&key).ok().and_then(|modified| modified) },
+                file: key,
+                layout: Layout::Files,
+            });
+        }
+    }
+    Ok(restores)
+}
+
+// objects of the nodes of the repo archived in the manifest of the snapshot
+fn stored(archive_dir: &str, archive_ext: &str, repo_type: RepoType) -> io::Result<Vec<Restore>> {
+    Ok(manifest::read_partial::<Entry>(&snapshot::manifest_file(archive_dir, archive_ext))?.into_iter()
+        .filter(|entry| entry.repo == repo_type && entry.action.is_archived())
+        .filter_map(|entry| {
+            let sha256 = entry.sha256.as_ref()?;
+            let file = objects::find(archive_dir, sha256).unwrap_or_else(|| objects::object_file(archive_dir, sha256, Compression::None));
+            Some(Restore{ file, path: entry.path_info(), layout: Layout::Objects })
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use link::Strategy;
+    use storage::Filesystem;
+    use backup::test_dir;
+
+    #[test]
+    fn test_plan_restore() {
+        let dir = test_dir("restore");
+        fs::create_dir_all(format!("{}/20180622/website/gato", dir)).unwrap();
+        for filename in &["gato.xml", "about.xml", "about%2Fstaff.xml", "aboutus.xml", "news%2F2018.xml.zst", "news%2F2018.xml.zst.part"] {
+            fs::write(format!("{}/20180622/website/gato/{}", dir, filename), "").unwrap();
+        }
+        let storage = Filesystem::new(&dir, Strategy::Hardlink);
+        let paths = |path: &str| -> Vec<String> {
+            plan(&storage, &dir, "20180622", RepoType::Website, path).unwrap().into_iter().map(|r| r.path.path).collect()
+        };
+        assert_eq!(paths("gato"), vec!["/gato"]);
+        assert_eq!(paths("/gato/about"), vec!["/gato/about"]);
+        assert_eq!(paths("/gato/news/"), vec!["/gato/news/2018"]);
+        assert_eq!(plan(&storage, &dir, "20180622", RepoType::Website, "/gato/about").unwrap()[0].file, "20180622/website/gato/about.xml");
+        let news = plan(&storage, &dir, "20180622", RepoType::Website, "/gato/news").unwrap();
+        assert_eq!(news[0].file, "20180622/website/gato/news%2F2018.xml.zst");
+        assert!(news[0].open(&storage).and_then(|mut export| export.read_to_end(&mut Vec::new())).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_plan_restore_objects() {
+        let dir = test_dir("restore-objects");
+        fs::create_dir(format!("{}/20180622", dir)).unwrap();
+        snapshot::set_layout(&dir, "20180622", Layout::Objects).unwrap();
+        let mut manifest = manifest::Writer::create(&snapshot::manifest_file(&dir, "20180622")).unwrap();
+        for (path, sha256) in &[("/gato/about", "aa"), ("/gato/about/staff", "bb"), ("/gato/aboutus", "cc")] {
+            let path = PathInfo{ repo_type: RepoType::Website, path: path.to_string(), last_modified: None };
+            manifest.write(&Entry::new(&path, manifest::Action::Linked, Some(1)).with_sha256(sha256.to_string())).unwrap();
+        }
+        let path = PathInfo{ repo_type: RepoType::Dam, path: "/gato/about".to_string(), last_modified: None };
+        manifest.write(&Entry::new(&path, manifest::Action::Exported, Some(1)).with_sha256("dd".to_string())).unwrap();
+        manifest.finish().unwrap();
+        let storage = Filesystem::new(&dir, Strategy::Hardlink);
+        let restores = plan(&storage, &dir, "20180622", RepoType::Website, "/gato/about").unwrap();
+        assert_eq!(restores.len(), 1);
+        assert_eq!(restores[0].file, format!("{}/.objects/aa/aa.xml", dir));
+        assert_eq!(plan(&storage, &dir, "20180622", RepoType::Website, "gato").unwrap().len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
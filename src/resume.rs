@@ -0,0 +1,93 @@
+use std::io;
+use std::fs;
+use serde_json;
+use failure::Error;
+use repos::RepoType;
+use nodes::PathInfo;
+use deletions::Listing;
+use backup;
+
+/// Work left over by a run that was shut down, saved into its snapshot
+/// so the next run of the same snapshot picks up where it left off.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Remaining {
+    /// Repos and sites listed so far, their paths are those in the manifest
+    pub listing: Listing,
+    /// Run level errors so far
+    pub errors: Vec<String>,
+    /// Repos whose sites were not listed
+    pub repos: Vec<RepoType>,
+    /// Sites whose paths were not listed
+    pub sites: Vec<PathInfo>,
+    /// Paths listed but not archived
+    pub paths: Vec<PathInfo>,
+}
+
+impl Remaining {
+    pub fn new() -> Remaining {
+        Remaining::default()
+    }
+
+    /// Add the repos, sites, and paths left over by another thread
+    pub fn merge(&mut self, other: Remaining) {
+        self.repos.extend(other.repos);
+        self.sites.extend(other.sites);
+        self.paths.extend(other.paths);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.repos.is_empty() && self.sites.is_empty() && self.paths.is_empty()
+    }
+
+    pub fn save(&self, file: &str) -> io::Result<u64> {
+        backup::write_file(file, &mut serde_json::to_vec(self)?.as_slice(), None)
+    }
+}
+
+/// Read the work left over in a snapshot, if any
+pub fn load(file: &str) -> Result<Option<Remaining>, Error> {
+    match fs::read(file) {
+        Ok(data) => Ok(Some(serde_json::from_slice(&data)?)),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use manifest::{Action, Entry};
+    use repos::Repo;
+    use backup::test_dir;
+
+    fn path(path: &str) -> PathInfo {
+        PathInfo{ repo_type: RepoType::Dam, path: path.to_string(), last_modified: Some("2016-06-30T12:17:18.324-05:00".parse().unwrap()) }
+    }
+
+    #[test]
+    fn test_save_and_load_remaining() {
+        let dir = test_dir("remaining");
+        let file = format!("{}/remaining.json", dir);
+        assert!(load(&file).unwrap().is_none());
+        let mut remaining = Remaining::new();
+        remaining.listing.repo(RepoType::Dam);
+        remaining.listing.failed_site(&path("/tx"));
+        remaining.listing.path(&path("/gato/a.gif"));
+        remaining.errors.push("Unable to list dam /tx".to_string());
+        remaining.repos.push(RepoType::Website);
+        remaining.sites.push(path("/bobcat"));
+        remaining.paths.push(path("/gato/b.gif"));
+        remaining.save(&file).unwrap();
+        let loaded = load(&file).unwrap().unwrap();
+        assert_eq!(loaded.errors, remaining.errors);
+        assert_eq!(loaded.repos, vec![RepoType::Website]);
+        assert_eq!(loaded.sites, remaining.sites);
+        assert_eq!(loaded.paths, remaining.paths);
+        // listed paths are left to the manifest
+        let repos = vec![Repo::new(RepoType::Dam, None)];
+        let entry = |path: &str| Entry::new(&PathInfo{ repo_type: RepoType::Dam, path: path.to_string(), last_modified: None }, Action::Exported, None);
+        assert!(loaded.listing.is_deleted(&repos, &entry("/gato/a.gif")));
+        assert!(!loaded.listing.is_deleted(&repos, &entry("/tx/c.gif")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,182 @@
+use std::time::Duration;
+use serde_json::{self, Value};
+use failure::Error;
+use rand::{self, Rng};
+use fetch::FetchError;
+
+/// Retry settings for one class of FetchError
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Retry {
+    /// Most attempts of a request, including the first
+    pub attempts: u32,
+    /// Delay before the first retry, doubled for each retry after it
+    pub delay_secs: f64,
+    pub max_delay_secs: f64,
+}
+
+impl Retry {
+    /// Exponential backoff with jitter before the nth retry (starting at 1),
+    /// randomly between half and all of the full delay.
+    pub fn delay(&self, retry: u32) -> Duration {
+        let delay = (self.delay_secs * 2f64.powi(retry.saturating_sub(1).min(30) as i32)).min(self.max_delay_secs);
+        if delay <= 0.0 {
+            return Duration::from_secs(0);
+        }
+        Duration::from_secs_f64(rand::thread_rng().gen_range(delay / 2.0, delay))
+    }
+}
+
+/// How failed requests are retried. Blocking errors are never retried.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    pub lost_session: Retry,
+    pub back_off: Retry,
+    pub transport: Retry,
+    pub invalid: Retry,
+    pub skip: Retry,
+    /// Most retries of any class spent on a single path
+    pub budget: u32,
+    /// Revisit paths that ran out of attempts once all others are done
+    pub deferred: bool,
+}
+
+impl Default for Policy {
+    // NOTE: Magnolia will keep generating 500's for some exports, so back off
+    //   only retries once and leaves the rest to the deferred pass.
+    fn default() -> Policy {
+        Policy{
+            lost_session: Retry{ attempts: 3, delay_secs: 0.0, max_delay_secs: 0.0 },
+            back_off: Retry{ attempts: 2, delay_secs: 15.0, max_delay_secs: 120.0 },
+            transport: Retry{ attempts: 3, delay_secs: 5.0, max_delay_secs: 60.0 },
+            invalid: Retry{ attempts: 2, delay_secs: 5.0, max_delay_secs: 60.0 },
+            skip: Retry{ attempts: 1, delay_secs: 0.0, max_delay_secs: 0.0 },
+            budget: 4,
+            deferred: true,
+        }
+    }
+}
+
+fn merge(base: &mut Value, settings: Value) {
+    match (base, settings) {
+        (&mut Value::Object(ref mut base), Value::Object(settings)) => {
+            for (key, value) in settings {
+                match base.get_mut(&key) {
+                    Some(base) => merge(base, value),
+                    None => { base.insert(key, value); },
+                }
+            }
+        },
+        (base, settings) => *base = settings,
+    }
+}
+
+/// Parse a retry policy where any settings left out keep their default:
+///   {"back_off": {"attempts": 4, "max_delay_secs": 300}, "budget": 6}
+pub fn new(settings: Value) -> Result<Policy, Error> {
+    let mut policy = serde_json::to_value(Policy::default())?;
+    merge(&mut policy, settings);
+    Ok(serde_json::from_value(policy)?)
+}
+
+/// Attempts made on a single path against its policy
+#[derive(Debug, Clone)]
+pub struct Attempts {
+    policy: Policy,
+    counts: [u32; 5],
+    retries: u32,
+}
+
+impl Attempts {
+    pub fn new(policy: Policy) -> Attempts {
+        Attempts{ policy, counts: [0; 5], retries: 0 }
+    }
+
+    /// Count a failed attempt, returning the delay before retrying
+    /// or None when the error is not to be retried.
+    pub fn retry(&mut self, error: &FetchError) -> Option<Duration> {
+        let (class, retry) = match error {
+            FetchError::LostSession{..} => (0, &self.policy.lost_session),
+            FetchError::BackOff{..} => (1, &self.policy.back_off),
+            FetchError::Transport{..} => (2, &self.policy.transport),
+            FetchError::Invalid{..} => (3, &self.policy.invalid),
+            FetchError::Skip{..} => (4, &self.policy.skip),
+            FetchError::Blocking{..} => return None,
+        };
+        self.counts[class] += 1;
+        if self.counts[class] >= retry.attempts || self.retries >= self.policy.budget {
+            return None;
+        }
+        self.retries += 1;
+        Some(retry.delay(self.counts[class]))
+    }
+
+    /// Count handing the path to another worker against its retry budget
+    pub fn requeue(&mut self) -> bool {
+        if self.retries >= self.policy.budget {
+            return false;
+        }
+        self.retries += 1;
+        true
+    }
+
+    /// Defer the path to be revisited once all others are done, starting over
+    /// its attempts while what remains of its retry budget carries over.
+    pub fn defer(&mut self) -> bool {
+        if !self.policy.deferred || self.retries >= self.policy.budget {
+            return false;
+        }
+        self.retries += 1;
+        self.counts = [0; 5];
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn back_off() -> FetchError {
+        FetchError::BackOff{ error: "500 Internal Server Error".to_string() }
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let retry = Retry{ attempts: 5, delay_secs: 10.0, max_delay_secs: 30.0 };
+        for _ in 0..20 {
+            let first = retry.delay(1);
+            assert!(first >= Duration::from_secs(5) && first <= Duration::from_secs(10));
+            let second = retry.delay(2);
+            assert!(second >= Duration::from_secs(10) && second <= Duration::from_secs(20));
+            assert!(retry.delay(40) <= Duration::from_secs(30));
+        }
+        assert_eq!(Retry{ attempts: 3, delay_secs: 0.0, max_delay_secs: 0.0 }.delay(2), Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_retry_attempts() {
+        let mut attempts = Attempts::new(Policy::default());
+        assert!(attempts.retry(&back_off()).is_some());
+        assert_eq!(attempts.retry(&back_off()), None);
+        assert_eq!(attempts.retry(&FetchError::Skip{ error: "parse".to_string() }), None);
+        assert_eq!(attempts.retry(&FetchError::Blocking{ error: "403 Forbidden".to_string() }), None);
+        assert!(attempts.defer());
+        assert!(attempts.retry(&back_off()).is_some());
+        assert!(attempts.retry(&FetchError::LostSession{ error: "302 Found".to_string() }).is_some());
+        // budget of 4 retries is spent
+        assert_eq!(attempts.retry(&FetchError::Transport{ error: "timeout".to_string() }), None);
+        assert!(!attempts.defer());
+        assert!(!attempts.requeue());
+    }
+
+    #[test]
+    fn test_policy_settings() {
+        let policy = new(serde_json::from_str(r#"{"back_off": {"attempts": 4}, "budget": 6, "deferred": false}"#).unwrap()).unwrap();
+        assert_eq!(policy.back_off, Retry{ attempts: 4, delay_secs: 15.0, max_delay_secs: 120.0 });
+        assert_eq!(policy.budget, 6);
+        assert!(!policy.deferred);
+        assert_eq!(policy.transport, Policy::default().transport);
+        assert!(new(serde_json::from_str(r#"{"budget": "many"}"#).unwrap()).is_err());
+    }
+}
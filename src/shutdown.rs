@@ -0,0 +1,46 @@
+use std::io;
+use std::thread;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::flag;
+
+/// Exit code when a second signal ends the run without waiting on in-flight exports
+pub const EXIT_FORCED: i32 = 130;
+
+const POLL: Duration = Duration::from_millis(100);
+
+lazy_static!{
+    static ref REQUESTED: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+}
+
+/// Request a graceful shutdown on SIGTERM or SIGINT. A second signal
+/// exits right away, any partial exports are removed by the next run.
+pub fn register() -> io::Result<()> {
+    for &signal in &[SIGTERM, SIGINT] {
+        // only exits once the flag was already set by an earlier signal
+        flag::register_conditional_shutdown(signal, EXIT_FORCED, REQUESTED.clone())?;
+        flag::register(signal, REQUESTED.clone())?;
+    }
+    Ok(())
+}
+
+pub fn is_requested() -> bool {
+    REQUESTED.load(Ordering::Relaxed)
+}
+
+/// Sleep unless a shutdown is requested, returning whether it slept the whole duration
+pub fn sleep(duration: Duration) -> bool {
+    let until = Instant::now() + duration;
+    loop {
+        if is_requested() {
+            return false;
+        }
+        let now = Instant::now();
+        if now >= until {
+            return true;
+        }
+        thread::sleep((until - now).min(POLL));
+    }
+}
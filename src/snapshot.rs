@@ -0,0 +1,209 @@
+use std::io::{self, Write};
+use std::fs::{self, File};
+use std::str::FromStr;
+use std::fmt::{self, Display};
+use chrono::{Local, NaiveDate};
+use failure::{Error, err_msg};
+
+/// Manifest of every node within a snapshot, see manifest::Entry
+pub const MANIFEST: &str = "manifest.jsonl";
+
+/// Nodes found in the previous snapshot that no longer exist, see deletions::Deletion
+pub const DELETIONS: &str = "deletions.jsonl";
+
+/// Summary of the run that took the snapshot, see summary::Summary
+pub const SUMMARY: &str = "summary.json";
+
+/// Work left over by a run that was shut down, see resume::Remaining
+pub const REMAINING: &str = "remaining.json";
+
+/// Marker written last once a snapshot has been completed
+pub const COMPLETE: &str = "complete";
+
+/// Layout the exports of a snapshot are stored in, see Layout
+pub const LAYOUT: &str = "layout";
+
+/// How the exports of a snapshot are stored
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Layout {
+    /// Files laid out by backup::archive_path and backup::backup_filename
+    /// within the snapshot, unchanged nodes hard linked to previous snapshots
+    Files,
+    /// Objects stored once by content hash in the object store shared by all
+    /// snapshots, the manifest of the snapshot mapping paths to them
+    Objects,
+}
+
+impl FromStr for Layout {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "files" => Ok(Layout::Files),
+            "objects" => Ok(Layout::Objects),
+            _ => Err(err_msg("Invalid value")),
+        }
+    }
+}
+
+impl Display for Layout {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Layout::Files => "files",
+            Layout::Objects => "objects",
+        })
+    }
+}
+
+/// Snapshot directory like <ARCHIVE_DIR>/<ARCHIVE_EXT>
+pub fn dir(archive_dir: &str, archive_ext: &str) -> String {
+    format!("{}/{}", archive_dir, archive_ext)
+}
+
+pub fn manifest_file(archive_dir: &str, archive_ext: &str) -> String {
+    format!("{}/{}", dir(archive_dir, archive_ext), MANIFEST)
+}
+
+pub fn deletions_file(archive_dir: &str, archive_ext: &str) -> String {
+    format!("{}/{}", dir(archive_dir, archive_ext), DELETIONS)
+}
+
+pub fn summary_file(archive_dir: &str, archive_ext: &str) -> String {
+    format!("{}/{}", dir(archive_dir, archive_ext), SUMMARY)
+}
+
+pub fn remaining_file(archive_dir: &str, archive_ext: &str) -> String {
+    format!("{}/{}", dir(archive_dir, archive_ext), REMAINING)
+}
+
+fn complete_file(archive_dir: &str, archive_ext: &str) -> String {
+    format!("{}/{}", dir(archive_dir, archive_ext), COMPLETE)
+}
+
+fn layout_file(archive_dir: &str, archive_ext: &str) -> String {
+    format!("{}/{}", dir(archive_dir, archive_ext), LAYOUT)
+}
+
+/// Layout of a snapshot, those taken before layouts were recorded being files
+pub fn layout(archive_dir: &str, archive_ext: &str) -> io::Result<Layout> {
+    match fs::read_to_string(layout_file(archive_dir, archive_ext)) {
+        Ok(layout) => layout.trim().parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid layout {:?}", layout.trim()))),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(Layout::Files),
+        Err(e) => Err(e),
+    }
+}
+
+/// Record the layout of a snapshot
+pub fn set_layout(archive_dir: &str, archive_ext: &str, layout: Layout) -> io::Result<()> {
+    let mut file = File::create(layout_file(archive_dir, archive_ext))?;
+    writeln!(file, "{}", layout)?;
+    file.sync_all()
+}
+
+/// Determine if snapshot ran to completion
+pub fn is_complete(archive_dir: &str, archive_ext: &str) -> bool {
+    fs::metadata(complete_file(archive_dir, archive_ext)).is_ok()
+}
+
+/// Mark snapshot as completed with the time it finished.
+pub fn complete(archive_dir: &str, archive_ext: &str) -> io::Result<()> {
+    let mut file = File::create(complete_file(archive_dir, archive_ext))?;
+    writeln!(file, "{}", Local::now().to_rfc3339())?;
+    file.sync_all()
+}
+
+/// Remove completion marker of a snapshot that is about to be modified.
+pub fn incomplete(archive_dir: &str, archive_ext: &str) -> io::Result<()> {
+    match fs::remove_file(complete_file(archive_dir, archive_ext)) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Date a snapshot was taken from its ARCHIVE_EXT name such as 20180622 or 2018-06-22-Fri
+pub fn date(archive_ext: &str) -> Option<NaiveDate> {
+    archive_ext.get(..10).and_then(|d| NaiveDate::parse_from_str(d, "%Y-%m-%d").ok())
+        .or_else(|| archive_ext.get(..8).and_then(|d| NaiveDate::parse_from_str(d, "%Y%m%d").ok()))
+}
+
+/// Order of a snapshot among others, by the date it was taken and then by name.
+/// As 2018-06-23-Sat sorts before 20180622 by name alone, snapshots named either
+/// way are ordered by date, while those not named by date follow them by name.
+pub fn order(archive_ext: &str) -> (bool, Option<NaiveDate>, &str) {
+    let date = date(archive_ext);
+    (date.is_none(), date, archive_ext)
+}
+
+/// List the snapshots within archive dir ordered oldest to newest, see order.
+/// Hidden entries are not snapshots.
+pub fn list(archive_dir: &str) -> io::Result<Vec<String>> {
+    let mut snapshots = Vec::new();
+    for entry in fs::read_dir(archive_dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            if let Ok(name) = entry.file_name().into_string() {
+                if !name.starts_with('.') {
+                    snapshots.push(name);
+                }
+            }
+        }
+    }
+    snapshots.sort_by(|a, b| order(a).cmp(&order(b)));
+    Ok(snapshots)
+}
+
+/// Find up to count completed snapshots taken before archive ext, newest first.
+pub fn previous(archive_dir: &str, archive_ext: &str, count: usize) -> io::Result<Vec<String>> {
+    Ok(list(archive_dir)?.into_iter()
+        .filter(|ext| order(ext) < order(archive_ext) && is_complete(archive_dir, ext))
+        .rev()
+        .take(count)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backup::test_dir;
+
+    #[test]
+    fn test_snapshot_date() {
+        assert_eq!(date("20180622"), Some(NaiveDate::from_ymd_opt(2018, 6, 22).unwrap()));
+        assert_eq!(date("2018-06-22-Fri"), Some(NaiveDate::from_ymd_opt(2018, 6, 22).unwrap()));
+        assert_eq!(date("manual"), None);
+    }
+
+    #[test]
+    fn test_previous_snapshots() {
+        let dir = test_dir("previous");
+        for ext in &["20180619", "20180620", "20180621", "20180623", "20180624", ".objects"] {
+            fs::create_dir(format!("{}/{}", dir, ext)).unwrap();
+            complete(&dir, ext).unwrap();
+        }
+        fs::write(format!("{}/20180622", dir), "").unwrap();
+        incomplete(&dir, "20180620").unwrap();
+        assert_eq!(previous(&dir, "20180624", 2).unwrap(), vec!["20180623", "20180621"]);
+        assert_eq!(previous(&dir, "20180621", 5).unwrap(), vec!["20180619"]);
+        // snapshots named both ways are ordered by date
+        for ext in &["2018-06-25-Mon", "manual"] {
+            fs::create_dir(format!("{}/{}", dir, ext)).unwrap();
+            complete(&dir, ext).unwrap();
+        }
+        assert_eq!(list(&dir).unwrap(), vec!["20180619", "20180620", "20180621", "20180623", "20180624", "2018-06-25-Mon", "manual"]);
+        assert_eq!(previous(&dir, "20180626", 2).unwrap(), vec!["2018-06-25-Mon", "20180624"]);
+        assert_eq!(previous(&dir, "2018-06-24-Sun", 1).unwrap(), vec!["20180623"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_layout() {
+        let dir = test_dir("layout");
+        fs::create_dir(format!("{}/20180622", dir)).unwrap();
+        assert_eq!(layout(&dir, "20180622").unwrap(), Layout::Files);
+        set_layout(&dir, "20180622", Layout::Objects).unwrap();
+        assert_eq!(layout(&dir, "20180622").unwrap(), Layout::Objects);
+        fs::write(layout_file(&dir, "20180622"), "tape\n").unwrap();
+        assert_eq!(layout(&dir, "20180622").unwrap_err().kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
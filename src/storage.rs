@@ -0,0 +1,216 @@
+use std::io::{self, Read, Write};
+use std::fs::{self, DirBuilder, File};
+use std::path::Path;
+use std::collections::BTreeSet;
+use chrono::{DateTime, Local};
+use filetime::{set_file_times, FileTime};
+use nodes::PathInfo;
+use backup;
+use compress::{self, Compression};
+use link::{self, Strategy};
+use manifest::{self, Entry};
+
+/// Where the exports of the files layout are archived, each stored under a key
+/// of its path within ARCHIVE_DIR like <ARCHIVE_EXT>/<repo>/<site>/<encoded>.xml
+/// along with the last modified time of its node, by which unchanged nodes
+/// are found.
+pub trait Storage: Send + Sync {
+    /// Store everything copy writes under key with the last modified time of its
+    /// node, returning what copy returns. Any export already stored under key is
+    /// only replaced once copy succeeds.
+    fn put(&self, key: &str, last_modified: Option<&DateTime<Local>>, copy: &mut dyn FnMut(&mut dyn Write) -> io::Result<u64>) -> io::Result<u64>;
+
+    /// Read the export stored under key as it was stored, still compressed
+    fn get(&self, key: &str) -> io::Result<Box<dyn Read + Send>>;
+
+    /// Link the export stored under from to key to along with its last modified
+    /// time, or copy it, returning whether it fell back to a copy as it could not
+    /// be linked the way this storage links.
+    fn link(&self, from: &str, to: &str) -> io::Result<bool>;
+
+    /// Keys of the exports stored within prefix, such as <ARCHIVE_EXT>/<repo>/<site>
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>>;
+
+    /// Last modified time stored with the export under key, None when it was
+    /// stored without one. Errors with NotFound when there is no such export.
+    fn modified(&self, key: &str) -> io::Result<Option<DateTime<Local>>>;
+
+    /// Remove the export stored under key, if there is one
+    fn remove(&self, key: &str) -> io::Result<()>;
+}
+
+/// Exports kept as files within ARCHIVE_DIR, their modified time set to the
+/// last modified time of their node, carried over from one snapshot to the
+/// next per the link strategy.
+pub struct Filesystem {
+    dir: String,
+    link: Strategy,
+}
+
+impl Filesystem {
+    pub fn new(dir: &str, link: Strategy) -> Filesystem {
+        Filesystem{ dir: dir.to_string(), link }
+    }
+
+    fn file(&self, key: &str) -> String {
+        format!("{}/{}", self.dir, key)
+    }
+
+    // file of key with the directory it is in created
+    fn create(&self, key: &str) -> io::Result<String> {
+        let file = self.file(key);
+        if let Some(dir) = Path::new(&file).parent() {
+            DirBuilder::new().recursive(true).create(dir)?;
+        }
+        Ok(file)
+    }
+}
+
+impl Storage for Filesystem {
+    fn put(&self, key: &str, last_modified: Option<&DateTime<Local>>, copy: &mut dyn FnMut(&mut dyn Write) -> io::Result<u64>) -> io::Result<u64> {
+        backup::write_file_with(&self.create(key)?, last_modified, |temp| copy(temp))
+    }
+
+    fn get(&self, key: &str) -> io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(File::open(self.file(key))?))
+    }
+
+    fn link(&self, from: &str, to: &str) -> io::Result<bool> {
+        let (from, to) = (self.file(from), self.create(to)?);
+        let modified = fs::metadata(&from)?.modified()?;
+        let used = link::carry(self.link, &from, &to)?;
+        // a hard link shares the modified time of the file it links to
+        if used != Strategy::Hardlink {
+            let timestamp = FileTime::from_system_time(modified);
+            set_file_times(&to, timestamp, timestamp)?;
+        }
+        Ok(used != self.link)
+    }
+
+    fn list(&self, prefix: &str) -> io::Result<Vec<String>> {
+        let mut keys = Vec::new();
+        for entry in fs::read_dir(self.file(prefix))? {
+            let entry = entry?;
+            if let Ok(filename) = entry.file_name().into_string() {
+                if entry.file_type()?.is_file() && !filename.ends_with(&format!(".{}", backup::TEMP_EXT)) {
+                    keys.push(format!("{}/{}", prefix, filename));
+                }
+            }
+        }
+        Ok(keys)
+    }
+
+    fn modified(&self, key: &str) -> io::Result<Option<DateTime<Local>>> {
+        Ok(Some(DateTime::from(fs::metadata(self.file(key))?.modified()?)))
+    }
+
+    fn remove(&self, key: &str) -> io::Result<()> {
+        match fs::remove_file(self.file(key)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+}
+
+/// Key of the export of path archived within archive_key, like that of
+/// backup::archive_key, whatever its compression along with its last modified time
+pub fn archived(storage: &dyn Storage, archive_key: &str, path: &PathInfo) -> io::Result<Option<(String, Option<DateTime<Local>>)>> {
+    for &compression in Compression::ALL.iter() {
+        let key = format!("{}/{}", archive_key, backup::backup_filename(path, compression));
+        match storage.modified(&key) {
+            Ok(modified) => return Ok(Some((key, modified))),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(None)
+}
+
+/// Remove the exports of path archived within archive_key other than the one
+/// compressed with except, so a snapshot never holds two copies of a node.
+pub fn remove_archived(storage: &dyn Storage, archive_key: &str, path: &PathInfo, except: Option<Compression>) -> io::Result<()> {
+    for &compression in Compression::ALL.iter().filter(|&&compression| Some(compression) != except) {
+        storage.remove(&format!("{}/{}", archive_key, backup::backup_filename(path, compression)))?;
+    }
+    Ok(())
+}
+
+/// Remove every export stored within the sites of snapshot ext recorded in its
+/// manifest, returning how many were removed. A snapshot without a manifest has
+/// nothing stored.
+pub fn remove_snapshot(storage: &dyn Storage, manifest_file: &str, ext: &str) -> io::Result<usize> {
+    let entries = match manifest::read_partial::<Entry>(manifest_file) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+        result => result?,
+    };
+    let sites: BTreeSet<(String, String)> = entries.into_iter().map(|entry| (entry.repo.to_string(), entry.site)).collect();
+    let mut removed = 0;
+    for (repo, site) in sites {
+        let keys = match storage.list(&format!("{}/{}/{}", ext, repo, site)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+            result => result?,
+        };
+        for key in keys {
+            storage.remove(&key)?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Read the export stored under key, decompressed per its suffix
+pub fn open(storage: &dyn Storage, key: &str) -> io::Result<Box<dyn Read + Send>> {
+    compress::reader(storage.get(key)?, Compression::of(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::os::unix::fs::MetadataExt;
+    use repos::RepoType;
+    use backup::test_dir;
+
+    #[test]
+    fn test_filesystem() {
+        let dir = test_dir("storage");
+        let storage = Filesystem::new(&dir, Strategy::Hardlink);
+        let last_modified = Local.timestamp_opt(1_500_000_000, 0).unwrap();
+        let path = PathInfo{ repo_type: RepoType::Dam, path: "/gato/a.gif".to_string(), last_modified: Some(last_modified) };
+        let key = "20180622/dam/gato/a%2Egif.xml.zst";
+        assert_eq!(archived(&storage, "20180622/dam/gato", &path).unwrap(), None);
+        let size = storage.put(key, Some(&last_modified), &mut |out| Compression::Zstd.write(out, |out| io::copy(&mut "<sv:node/>".as_bytes(), out))).unwrap();
+        assert_eq!(size, 10);
+        assert!(storage.put(key, None, &mut |_| Err(io::Error::other("connection reset"))).is_err());
+        assert_eq!(storage.modified(key).unwrap(), Some(last_modified));
+        assert_eq!(archived(&storage, "20180622/dam/gato", &path).unwrap(), Some((key.to_string(), Some(last_modified))));
+        assert_eq!(backup::sha256(open(&storage, key).unwrap()).unwrap().0, 10);
+        assert_eq!(storage.list("20180622/dam/gato").unwrap(), vec![key.to_string()]);
+
+        assert!(!storage.link(key, "20180623/dam/gato/a%2Egif.xml.zst").unwrap());
+        assert_eq!(fs::metadata(format!("{}/{}", dir, key)).unwrap().nlink(), 2);
+        let copies = Filesystem::new(&dir, Strategy::Copy);
+        assert!(!copies.link(key, "20180624/dam/gato/a%2Egif.xml.zst").unwrap());
+        assert_eq!(fs::metadata(format!("{}/{}", dir, key)).unwrap().nlink(), 2);
+        assert_eq!(copies.modified("20180624/dam/gato/a%2Egif.xml.zst").unwrap(), Some(last_modified));
+
+        // a copy with another compression left by an earlier run
+        storage.put("20180622/dam/gato/a%2Egif.xml", None, &mut |out| io::copy(&mut "<sv:node/>".as_bytes(), out)).unwrap();
+        remove_archived(&storage, "20180622/dam/gato", &path, Some(Compression::Zstd)).unwrap();
+        assert_eq!(storage.list("20180622/dam/gato").unwrap(), vec![key.to_string()]);
+        remove_archived(&storage, "20180622/dam/gato", &path, None).unwrap();
+        assert_eq!(storage.modified(key).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(storage.list("20180622/dam/gato").unwrap().is_empty());
+
+        let manifest_file = format!("{}/manifest.jsonl", dir);
+        assert_eq!(remove_snapshot(&storage, &manifest_file, "20180624").unwrap(), 0);
+        fs::write(&manifest_file, concat!(
+            r#"{"repo":"dam","site":"gato","path":"/gato/a.gif","last_modified":null,"size":10,"action":"linked","error":null}"#, "\n",
+            r#"{"repo":"dam","site":"bobcat","path":"/bobcat/b.gif","last_modified":null,"size":null,"action":"failed","error":"timed out"}"#, "\n",
+        )).unwrap();
+        assert_eq!(remove_snapshot(&storage, &manifest_file, "20180624").unwrap(), 1);
+        assert!(storage.list("20180624/dam/gato").unwrap().is_empty());
+        assert_eq!(storage.list("20180623/dam/gato").unwrap().len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
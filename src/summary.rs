@@ -0,0 +1,208 @@
+use std::io;
+use std::fs;
+use std::fmt::Write;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use serde_json;
+use chrono::{DateTime, Local};
+use manifest::{Action, Entry};
+
+/// Outcome of a run, each with its own process exit code
+#[derive(Serialize, Debug, Copy, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Success,
+    Partial,
+    Failed,
+    /// Shut down before it finished, the next run of the snapshot resumes it
+    Interrupted,
+}
+
+impl Status {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Status::Success => 0,
+            Status::Partial => 1,
+            Status::Failed => 2,
+            Status::Interrupted => 3,
+        }
+    }
+}
+
+/// Number of nodes per action along with the bytes exported
+#[derive(Serialize, Debug, Default, Clone, PartialEq)]
+pub struct Counts {
+    pub exported: usize,
+    pub linked: usize,
+    pub kept: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub bytes: u64,
+}
+
+impl Counts {
+    fn add(&mut self, entry: &Entry) {
+        match entry.action {
+            Action::Exported => {
+                self.exported += 1;
+                self.bytes += entry.size.unwrap_or(0);
+            },
+            Action::Linked => self.linked += 1,
+            Action::Kept => self.kept += 1,
+            Action::Skipped => self.skipped += 1,
+            Action::Failed => self.failed += 1,
+        }
+    }
+
+    pub fn merge(&mut self, other: &Counts) {
+        self.exported += other.exported;
+        self.linked += other.linked;
+        self.kept += other.kept;
+        self.skipped += other.skipped;
+        self.failed += other.failed;
+        self.bytes += other.bytes;
+    }
+
+    fn archived(&self) -> usize {
+        self.exported + self.linked + self.kept
+    }
+
+    fn unarchived(&self) -> usize {
+        self.skipped + self.failed
+    }
+}
+
+/// Summary of a backup run with counts per repo and site
+#[derive(Serialize, Debug)]
+pub struct Summary {
+    pub snapshot: String,
+    pub started: DateTime<Local>,
+    pub finished: Option<DateTime<Local>>,
+    pub duration_secs: u64,
+    pub status: Status,
+    pub total: Counts,
+    pub repos: BTreeMap<String, BTreeMap<String, Counts>>,
+    /// Repos and sites that could not be listed or other run level errors
+    pub errors: Vec<String>,
+    pub aborted: bool,
+    pub interrupted: bool,
+}
+
+impl Summary {
+    pub fn new(snapshot: &str) -> Summary {
+        Summary{
+            snapshot: snapshot.to_string(),
+            started: Local::now(),
+            finished: None,
+            duration_secs: 0,
+            status: Status::Success,
+            total: Counts::default(),
+            repos: BTreeMap::new(),
+            errors: Vec::new(),
+            aborted: false,
+            interrupted: false,
+        }
+    }
+
+    pub fn add(&mut self, entry: &Entry) {
+        self.total.add(entry);
+        self.repos.entry(entry.repo.to_string()).or_default()
+            .entry(entry.site.clone()).or_default()
+            .add(entry);
+    }
+
+    pub fn error<S: Into<String>>(&mut self, error: S) {
+        self.errors.push(error.into());
+    }
+
+    /// Finish the run determining its status. The run failed if it was aborted or
+    /// nothing could be archived, was interrupted if it was shut down before it
+    /// finished, and is partial if anything was not archived.
+    pub fn finish(&mut self, duration: Duration) -> Status {
+        self.finished = Some(Local::now());
+        self.duration_secs = duration.as_secs();
+        let problems = self.total.unarchived() + self.errors.len();
+        self.status = if self.aborted || (problems > 0 && self.total.archived() == 0) {
+            Status::Failed
+        } else if self.interrupted {
+            Status::Interrupted
+        } else if problems > 0 {
+            Status::Partial
+        } else {
+            Status::Success
+        };
+        self.status
+    }
+
+    pub fn save(&self, file: &str) -> io::Result<()> {
+        fs::write(file, serde_json::to_string_pretty(self)?)
+    }
+
+    /// Human readable report of the summary
+    pub fn report(&self) -> String {
+        let mut report = String::new();
+        let line = |report: &mut String, name: &str, counts: &Counts| {
+            writeln!(report, "{:<40} exported: {:>8} linked: {:>8} kept: {:>8} skipped: {:>6} failed: {:>6} bytes: {:>14}",
+                name, counts.exported, counts.linked, counts.kept, counts.skipped, counts.failed, counts.bytes).unwrap();
+        };
+        writeln!(report, "Snapshot {} {:?} in {}s", self.snapshot, self.status, self.duration_secs).unwrap();
+        for (repo, sites) in &self.repos {
+            for (site, counts) in sites {
+                line(&mut report, &format!("{}/{}", repo, site), counts);
+            }
+        }
+        line(&mut report, "total", &self.total);
+        for error in &self.errors {
+            writeln!(report, "error: {}", error).unwrap();
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nodes::PathInfo;
+    use repos::RepoType;
+
+    fn entry(path: &str, action: Action, size: Option<u64>) -> Entry {
+        Entry::new(&PathInfo{ repo_type: RepoType::Dam, path: path.to_string(), last_modified: None }, action, size)
+    }
+
+    #[test]
+    fn test_summary_counts() {
+        let mut summary = Summary::new("20180622");
+        summary.add(&entry("/gato/a.gif", Action::Exported, Some(100)));
+        summary.add(&entry("/gato/b.gif", Action::Linked, Some(50)));
+        summary.add(&entry("/tx/c.gif", Action::Exported, Some(10)));
+        assert_eq!(summary.total, Counts{ exported: 2, linked: 1, kept: 0, skipped: 0, failed: 0, bytes: 110 });
+        assert_eq!(summary.repos["dam"]["gato"], Counts{ exported: 1, linked: 1, kept: 0, skipped: 0, failed: 0, bytes: 100 });
+        assert_eq!(summary.finish(Duration::from_secs(5)), Status::Success);
+        summary.add(&entry("/tx/d.gif", Action::Skipped, None));
+        assert_eq!(summary.finish(Duration::from_secs(5)), Status::Partial);
+    }
+
+    #[test]
+    fn test_summary_failed() {
+        let mut summary = Summary::new("20180622");
+        summary.add(&entry("/gato/a.gif", Action::Failed, None));
+        assert_eq!(summary.finish(Duration::from_secs(5)), Status::Failed);
+        let mut summary = Summary::new("20180622");
+        summary.error("Unable to retrieve sites for repo dam");
+        assert_eq!(summary.finish(Duration::from_secs(5)), Status::Failed);
+        let mut summary = Summary::new("20180622");
+        summary.add(&entry("/gato/a.gif", Action::Exported, Some(1)));
+        summary.aborted = true;
+        assert_eq!(summary.finish(Duration::from_secs(5)).exit_code(), 2);
+    }
+
+    #[test]
+    fn test_summary_interrupted() {
+        let mut summary = Summary::new("20180622");
+        summary.add(&entry("/gato/a.gif", Action::Exported, Some(1)));
+        summary.add(&entry("/gato/b.gif", Action::Skipped, None));
+        summary.interrupted = true;
+        assert_eq!(summary.finish(Duration::from_secs(5)), Status::Interrupted);
+        assert_eq!(summary.status.exit_code(), 3);
+    }
+}
@@ -0,0 +1,179 @@
+use std::io::{self, BufReader, Read, Write};
+use quick_xml::Reader;
+use quick_xml::events::{BytesStart, Event};
+use backup::Hashing;
+
+/// Namespace of JCR system view XML
+pub const SV_NAMESPACE: &str = "http://www.jcp.org/jcr/sv/1.0";
+
+// Reader writing everything read through it to out
+struct Tee<R, W> {
+    reader: R,
+    out: W,
+    size: u64,
+}
+
+impl<R: Read, W: Write> Read for Tee<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.out.write_all(&buf[..read])?;
+        self.size += read as u64;
+        Ok(read)
+    }
+}
+
+fn invalid<S: Into<String>>(error: S) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.into())
+}
+
+// root of an export is the sv:node of the node requested
+fn check_root(root: &BytesStart, path: &str) -> io::Result<()> {
+    let element = String::from_utf8_lossy(root.name().as_ref()).into_owned();
+    if element != "sv:node" {
+        return Err(invalid(format!("root element {} is not sv:node", element)));
+    }
+    let mut name = None;
+    let mut namespace = None;
+    for attribute in root.attributes() {
+        let attribute = attribute.map_err(|e| invalid(e.to_string()))?;
+        let value = || attribute.unescape_value().map(|value| value.into_owned()).map_err(|e| invalid(e.to_string()));
+        match attribute.key.as_ref() {
+            b"sv:name" => name = Some(value()?),
+            b"xmlns:sv" => namespace = Some(value()?),
+            _ => (),
+        }
+    }
+    if namespace.as_deref() != Some(SV_NAMESPACE) {
+        return Err(invalid("root sv:node is not in the system view namespace"));
+    }
+    let expected = path.rsplit('/').next().unwrap_or(path);
+    match name {
+        Some(ref name) if name == expected => Ok(()),
+        Some(name) => Err(invalid(format!("root sv:node {} is not {}", name, expected))),
+        None => Err(invalid("root sv:node has no sv:name")),
+    }
+}
+
+/// Stream an export of the node at path into out, checking along the way that
+/// it is well-formed JCR system view XML whose root sv:node is that node, and
+/// return its size. Invalid exports fail with io::ErrorKind::InvalidData.
+pub fn copy<R: Read, W: Write>(export: R, out: W, path: &str) -> io::Result<u64> {
+    let mut tee = Tee{ reader: export, out, size: 0 };
+    {
+        let mut reader = Reader::from_reader(BufReader::new(&mut tee));
+        let mut buf = Vec::new();
+        let mut depth = 0usize;
+        let mut root = false;
+        loop {
+            let event = match reader.read_event_into(&mut buf) {
+                Ok(event) => event,
+                // failing to read or write the export is not the export's fault
+                Err(quick_xml::Error::Io(e)) => return Err(io::Error::new(e.kind(), e.to_string())),
+                Err(e) => return Err(invalid(e.to_string())),
+            };
+            match event {
+                Event::Start(ref element) | Event::Empty(ref element) if depth == 0 => {
+                    if root {
+                        return Err(invalid("more than one root element"));
+                    }
+                    check_root(element, path)?;
+                    root = true;
+                    if let Event::Start(_) = event {
+                        depth += 1;
+                    }
+                },
+                Event::Start(_) => depth += 1,
+                Event::End(_) => depth = depth.saturating_sub(1),
+                Event::Text(ref text) if depth == 0 && !text.iter().all(u8::is_ascii_whitespace) => {
+                    return Err(invalid("text outside of the root element"));
+                },
+                Event::Eof => break,
+                _ => (),
+            }
+            buf.clear();
+        }
+        if !root {
+            return Err(invalid("no root element"));
+        }
+        if depth > 0 {
+            return Err(invalid(format!("truncated with {} elements left open", depth)));
+        }
+    }
+    Ok(tee.size)
+}
+
+/// Size and SHA-256 of the archived export of the node at path, read already
+/// decompressed, checking it is still a valid export as it is read.
+pub fn archived<R: Read>(export: R, path: &str) -> io::Result<(u64, String)> {
+    let mut export = Hashing::new(export);
+    let size = copy(&mut export, io::sink(), path)?;
+    Ok((size, export.sha256()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{self, File};
+    use backup::{self, test_dir};
+    use compress::{self, Compression};
+
+    fn export(name: &str, body: &str) -> String {
+        format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+<sv:node xmlns:sv="{}" sv:name="{}"><sv:property sv:name="jcr:primaryType" sv:type="Name"><sv:value>mgnl:asset</sv:value></sv:property>{}</sv:node>
+"#, SV_NAMESPACE, name, body)
+    }
+
+    fn check(path: &str, data: &str) -> io::Result<u64> {
+        let mut out = Vec::new();
+        let result = copy(data.as_bytes(), &mut out, path);
+        // everything read is written out, valid or not
+        assert!(data.as_bytes().starts_with(&out));
+        result
+    }
+
+    #[test]
+    fn test_valid_export() {
+        let data = export("a &amp; b.gif", r#"<sv:node sv:name="jcr:content"/>"#);
+        assert_eq!(check("/gato/a & b.gif", &data).unwrap(), data.len() as u64);
+        assert!(check("/gato", &export("gato", "")).is_ok());
+    }
+
+    #[test]
+    fn test_invalid_export() {
+        let invalid = |path: &str, data: &str| {
+            let error = check(path, data).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{}", data);
+            error.to_string()
+        };
+        assert_eq!(invalid("/gato/a.gif", "<html><body>Internal error</body></html>"), "root element html is not sv:node");
+        assert_eq!(invalid("/gato/a.gif", &export("b.gif", "")), "root sv:node b.gif is not a.gif");
+        let data = export("a.gif", "");
+        assert_eq!(invalid("/gato/a.gif", &data[..data.len() - "</sv:node>\n".len()]), "truncated with 1 elements left open");
+        invalid("/gato/a.gif", &data[..data.len() - 20]);
+        assert_eq!(invalid("/gato/a.gif", ""), "no root element");
+        invalid("/gato/a.gif", &export("a.gif", "<sv:property></sv:node>"));
+        invalid("/gato/a.gif", &format!("{}{}", data, data));
+        invalid("/gato/a.gif", r#"<sv:node sv:name="a.gif"></sv:node>"#);
+    }
+
+    #[test]
+    fn test_archived() {
+        let dir = test_dir("archived-export");
+        let data = export("a.gif", "");
+        let mut hashing = Hashing::new(data.as_bytes());
+        io::copy(&mut hashing, &mut io::sink()).unwrap();
+        let expected = hashing.sha256();
+        for &compression in &Compression::ALL {
+            let file = format!("{}/a%2Egif.xml{}", dir, compression.suffix());
+            compression.write(File::create(&file).unwrap(), |out| io::copy(&mut data.as_bytes(), out)).unwrap();
+            let (size, sha256) = archived(compress::open(&file).unwrap(), "/gato/a.gif").unwrap();
+            assert_eq!((size, sha256.clone()), backup::sha256_file(&file).unwrap());
+            assert_eq!(size, data.len() as u64);
+            assert_eq!(sha256, expected);
+            assert_eq!(archived(compress::open(&file).unwrap(), "/gato/b.gif").unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+        fs::write(format!("{}/a%2Egif.xml", dir), &data[..data.len() - 20]).unwrap();
+        assert!(archived(compress::open(&format!("{}/a%2Egif.xml", dir)).unwrap(), "/gato/a.gif").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
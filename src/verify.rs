@@ -0,0 +1,109 @@
+use std::io::{self, Read};
+use manifest::Entry;
+use backup;
+use compress::Compression;
+use snapshot::Layout;
+use objects;
+use storage::{self, Storage};
+
+/// Outcome of rehashing the archived file of a manifest entry
+#[derive(Debug, PartialEq)]
+pub enum Check {
+    Intact,
+    /// Manifest recorded no checksum, so only the size was checked
+    Unchecked,
+    Missing,
+    Corrupted(String),
+}
+
+/// Archived file of a manifest entry within a snapshot, or its object when the
+/// snapshot has the objects layout, whatever its compression or the uncompressed
+/// file it would be when missing. None when an object has no checksum to find it by.
+pub fn archive_file(archive_dir: &str, archive_ext: &str, layout: Layout, entry: &Entry) -> Option<String> {
+    if layout == Layout::Objects {
+        return entry.sha256.as_ref().map(|sha256| {
+            objects::find(archive_dir, sha256).unwrap_or_else(|| objects::object_file(archive_dir, sha256, Compression::None))
+        });
+    }
+    let path = entry.path_info();
+    let archive_path = backup::archive_path(archive_dir, archive_ext, &path);
+    Some(backup::archived_file(&archive_path, &path)
+        .unwrap_or_else(|| format!("{}/{}", archive_path, backup::backup_filename(&path, Compression::None))))
+}
+
+/// Key in storage of the archived export of a manifest entry of the files layout,
+/// whatever its compression or the uncompressed export it would be when missing
+pub fn archive_key(storage: &dyn Storage, archive_ext: &str, entry: &Entry) -> String {
+    let path = entry.path_info();
+    let archive_key = backup::archive_key(archive_ext, &path);
+    match storage::archived(storage, &archive_key, &path) {
+        Ok(Some((key, _))) => key,
+        _ => format!("{}/{}", archive_key, backup::backup_filename(&path, Compression::None)),
+    }
+}
+
+/// Rehash an archived export, opened decompressed, and compare it against the
+/// size and checksum recorded in entry
+pub fn check<R: Read>(export: io::Result<R>, entry: &Entry) -> Check {
+    let (size, sha256) = match export.and_then(backup::sha256) {
+        Ok(sum) => sum,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Check::Missing,
+        Err(e) => return Check::Corrupted(e.to_string()),
+    };
+    if let Some(expected) = entry.size.filter(|&expected| expected != size) {
+        return Check::Corrupted(format!("{} bytes instead of {}", size, expected));
+    }
+    match entry.sha256 {
+        None => Check::Unchecked,
+        Some(ref expected) if *expected == sha256 => Check::Intact,
+        Some(ref expected) => Check::Corrupted(format!("sha256 {} instead of {}", sha256, expected)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use manifest::Action;
+    use nodes::PathInfo;
+    use repos::RepoType;
+    use backup::test_dir;
+    use compress;
+    use link::Strategy;
+    use storage::Filesystem;
+
+    #[test]
+    fn test_check() {
+        let dir = test_dir("verify");
+        let path = PathInfo{ repo_type: RepoType::Dam, path: "/gato/a.gif".to_string(), last_modified: None };
+        let entry = Entry::new(&path, Action::Exported, Some(10)).with_sha256("b80936834c3322f32424fe8a5b4df5732e10a0dced6b7de2c7003d82a7115fb0".to_string());
+        let file = archive_file(&dir, "20180622", Layout::Files, &entry).unwrap();
+        assert_eq!(file, format!("{}/20180622/dam/gato/a%2Egif.xml", dir));
+        assert_eq!(check(compress::open(&file), &entry), Check::Missing);
+        fs::create_dir_all(format!("{}/20180622/dam/gato", dir)).unwrap();
+        fs::write(&file, "<sv:node/>").unwrap();
+        assert_eq!(check(compress::open(&file), &entry), Check::Intact);
+        assert_eq!(check(compress::open(&file), &Entry{ sha256: None, ..entry.clone() }), Check::Unchecked);
+        fs::write(&file, "<sv:nope/>").unwrap();
+        match check(compress::open(&file), &entry) {
+            Check::Corrupted(reason) => assert!(reason.starts_with("sha256 ")),
+            check => panic!("expected corrupted, {:?}", check),
+        }
+        fs::write(&file, "<sv:node").unwrap();
+        assert_eq!(check(compress::open(&file), &entry), Check::Corrupted("8 bytes instead of 10".to_string()));
+        fs::remove_file(&file).unwrap();
+        let compressed = format!("{}.zst", file);
+        Compression::Zstd.write(fs::File::create(&compressed).unwrap(), |out| out.write_all(b"<sv:node/>").map(|_| 10)).unwrap();
+        assert_eq!(archive_file(&dir, "20180622", Layout::Files, &entry), Some(compressed.clone()));
+        assert_eq!(check(compress::open(&compressed), &entry), Check::Intact);
+        let storage = Filesystem::new(&dir, Strategy::Hardlink);
+        assert_eq!(archive_key(&storage, "20180622", &entry), "20180622/dam/gato/a%2Egif.xml.zst");
+        assert_eq!(check(storage::open(&storage, "20180622/dam/gato/a%2Egif.xml.zst"), &entry), Check::Intact);
+        assert_eq!(archive_key(&storage, "20180623", &entry), "20180623/dam/gato/a%2Egif.xml");
+        assert_eq!(check(storage::open(&storage, "20180623/dam/gato/a%2Egif.xml"), &entry), Check::Missing);
+        let object = objects::object_file(&dir, entry.sha256.as_ref().unwrap(), Compression::None);
+        assert_eq!(archive_file(&dir, "20180622", Layout::Objects, &entry), Some(object));
+        assert_eq!(archive_file(&dir, "20180622", Layout::Objects, &Entry{ sha256: None, ..entry.clone() }), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}